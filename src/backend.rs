@@ -0,0 +1,244 @@
+//! Pluggable backends performing the actual filesystem writes of a copy.
+use std::fmt::Debug;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+/// How much of a file [`StdBackend`] copies between progress reports
+const PROGRESS_CHUNK: usize = 128 * 1024;
+
+/// The operations a [`CopyBuilder`](crate::CopyBuilder) performs on the destination.
+///
+/// Traversal, filtering and overwrite decisions stay in the builder, so an implementation
+/// only has to know how to write a single entry.
+pub trait CopyBackend: Debug + Send + Sync {
+    /// Copy the contents of the regular file `src` to `dst`, returning the bytes written
+    fn copy_file(&self, src: &Path, dst: &Path) -> io::Result<u64>;
+
+    /// Like [`CopyBackend::copy_file`], calling `progress` with the bytes written so far as the
+    /// copy goes on. Only used with [`CopyBuilder::on_progress`](crate::CopyBuilder::on_progress).
+    ///
+    /// By default `progress` is only called once the whole file is copied.
+    fn copy_file_with_progress(
+        &self,
+        src: &Path,
+        dst: &Path,
+        progress: &dyn Fn(u64),
+    ) -> io::Result<u64> {
+        let len = self.copy_file(src, dst)?;
+        progress(len);
+        Ok(len)
+    }
+
+    /// Like [`CopyBackend::copy_file_with_progress`], reading and writing up to `buffer_size`
+    /// bytes at a time. Only used with
+    /// [`CopyBuilder::buffer_size`](crate::CopyBuilder::buffer_size).
+    ///
+    /// By default the buffer size is ignored.
+    fn copy_file_buffered(
+        &self,
+        src: &Path,
+        dst: &Path,
+        buffer_size: usize,
+        progress: &dyn Fn(u64),
+    ) -> io::Result<u64> {
+        let _ = buffer_size;
+        self.copy_file_with_progress(src, dst, progress)
+    }
+
+    /// Make `dst` a copy-on-write clone of the regular file `src`, sharing its data blocks,
+    /// returning the length of the file. Only used with
+    /// [`CopyBuilder::reflink`](crate::CopyBuilder::reflink).
+    ///
+    /// Not supported by default.
+    fn reflink_file(&self, src: &Path, dst: &Path) -> io::Result<u64> {
+        let _ = dst;
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("Cannot reflink {}", src.display()),
+        ))
+    }
+
+    /// Create a symlink at `link` pointing to `target`
+    fn create_symlink(&self, target: &Path, link: &Path) -> io::Result<()>;
+
+    /// Create a symlink at `link` pointing to `target`, which resolves to a directory.
+    ///
+    /// Only differs from [`CopyBackend::create_symlink`] on platforms telling the two kinds of
+    /// symlinks apart, like Windows. Same as it by default.
+    fn create_dir_symlink(&self, target: &Path, link: &Path) -> io::Result<()> {
+        self.create_symlink(target, link)
+    }
+
+    /// Create the directory `dst`, along with any missing parents
+    fn create_dir(&self, dst: &Path) -> io::Result<()>;
+
+    /// Recreate the special file `src`, such as a socket, FIFO or device node, at `dst`.
+    ///
+    /// Only called when recreating special files is enabled. Not supported by default.
+    fn create_special(&self, src: &Path, dst: &Path) -> io::Result<()> {
+        let _ = dst;
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("Cannot recreate special file {}", src.display()),
+        ))
+    }
+}
+
+/// The default backend, writing to the local filesystem with `std::fs`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StdBackend;
+
+impl CopyBackend for StdBackend {
+    fn copy_file(&self, src: &Path, dst: &Path) -> io::Result<u64> {
+        std::fs::copy(src, dst)
+    }
+
+    /// Copy in chunks instead of letting the kernel copy the whole file, like
+    /// [`std::fs::copy`] does, so progress can be reported in between
+    fn copy_file_with_progress(
+        &self,
+        src: &Path,
+        dst: &Path,
+        progress: &dyn Fn(u64),
+    ) -> io::Result<u64> {
+        self.copy_file_buffered(src, dst, PROGRESS_CHUNK, progress)
+    }
+
+    /// Copy through a buffer of `buffer_size` bytes. A short write fails with
+    /// [`io::ErrorKind::WriteZero`], like [`io::copy`].
+    fn copy_file_buffered(
+        &self,
+        src: &Path,
+        dst: &Path,
+        buffer_size: usize,
+        progress: &dyn Fn(u64),
+    ) -> io::Result<u64> {
+        let mut reader = File::open(src)?;
+        let permissions = reader.metadata()?.permissions();
+        let mut writer = File::create(dst)?;
+        let mut buffer = vec![0; buffer_size.max(1)];
+        let mut written = 0;
+        loop {
+            let read = match reader.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(read) => read,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            writer.write_all(&buffer[..read])?;
+            written += read as u64;
+            progress(written);
+        }
+        if written == 0 {
+            progress(0);
+        }
+        // Like std::fs::copy, the copy gets the permissions of the source
+        writer.set_permissions(permissions)?;
+        Ok(written)
+    }
+
+    /// Clone with the `FICLONE` ioctl, supported by Btrfs and XFS among others
+    #[cfg(target_os = "linux")]
+    fn reflink_file(&self, src: &Path, dst: &Path) -> io::Result<u64> {
+        use std::os::fd::AsRawFd;
+
+        let reader = File::open(src)?;
+        let metadata = reader.metadata()?;
+        let writer = File::create(dst)?;
+        // SAFETY: both file descriptors stay open for the duration of the call
+        if unsafe { libc::ioctl(writer.as_raw_fd(), libc::FICLONE, reader.as_raw_fd()) } != 0 {
+            let e = io::Error::last_os_error();
+            drop(writer);
+            // Do not leave the empty file behind
+            let _ = std::fs::remove_file(dst);
+            return Err(e);
+        }
+        writer.set_permissions(metadata.permissions())?;
+        Ok(metadata.len())
+    }
+
+    /// Clone with `clonefile`, supported by APFS
+    #[cfg(target_os = "macos")]
+    fn reflink_file(&self, src: &Path, dst: &Path) -> io::Result<u64> {
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+
+        let len = src.metadata()?.len();
+        // clonefile refuses to replace an existing file
+        if dst.symlink_metadata().is_ok() {
+            std::fs::remove_file(dst)?;
+        }
+        let src = CString::new(src.as_os_str().as_bytes())?;
+        let dst = CString::new(dst.as_os_str().as_bytes())?;
+        // SAFETY: both paths are valid NUL terminated strings
+        if unsafe { libc::clonefile(src.as_ptr(), dst.as_ptr(), 0) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(len)
+    }
+
+    fn create_symlink(&self, target: &Path, link: &Path) -> io::Result<()> {
+        #[cfg(unix)]
+        return std::os::unix::fs::symlink(target, link);
+        #[cfg(windows)]
+        return explain_privilege(std::os::windows::fs::symlink_file(target, link), link);
+        #[cfg(not(any(unix, windows)))]
+        {
+            let _ = (target, link);
+            Ok(())
+        }
+    }
+
+    #[cfg(windows)]
+    fn create_dir_symlink(&self, target: &Path, link: &Path) -> io::Result<()> {
+        explain_privilege(std::os::windows::fs::symlink_dir(target, link), link)
+    }
+
+    fn create_dir(&self, dst: &Path) -> io::Result<()> {
+        std::fs::create_dir_all(dst)
+    }
+
+    #[cfg(unix)]
+    fn create_special(&self, src: &Path, dst: &Path) -> io::Result<()> {
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+        use std::os::unix::fs::MetadataExt;
+
+        // mknod creates sockets and FIFOs as well as device nodes, which need CAP_MKNOD
+        let metadata = src.symlink_metadata()?;
+        let path = CString::new(dst.as_os_str().as_bytes())?;
+        // SAFETY: `path` is a valid NUL terminated string
+        let ret = unsafe {
+            libc::mknod(
+                path.as_ptr(),
+                metadata.mode() as libc::mode_t,
+                metadata.rdev() as libc::dev_t,
+            )
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+/// Point out how to get the privilege to create symlinks on Windows if `result` failed for
+/// lack of it
+#[cfg(windows)]
+fn explain_privilege(result: io::Result<()>, link: &Path) -> io::Result<()> {
+    /// ERROR_PRIVILEGE_NOT_HELD
+    const PRIVILEGE_NOT_HELD: i32 = 1314;
+    result.map_err(|e| match e.raw_os_error() {
+        Some(PRIVILEGE_NOT_HELD) => io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "Not permitted to create symlink {}, which requires Developer Mode or an \
+                 elevated prompt on Windows: {}",
+                link.display(),
+                e
+            ),
+        ),
+        _ => e,
+    })
+}
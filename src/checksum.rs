@@ -0,0 +1,165 @@
+//! Read `sha256sum` manifests and hash files to check against them, and hash whole trees.
+use sha2::{Digest, Sha256, Sha512};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+/// The hash function of a tree hash, see
+/// [`CopyBuilder::with_tree_hash`](crate::CopyBuilder::with_tree_hash)
+pub enum HashAlgo {
+    /// SHA-256
+    #[default]
+    Sha256,
+    /// SHA-512
+    Sha512,
+}
+
+/// The hex encoded SHA-256 hash of the contents of the file at `path`
+pub(crate) fn sha256(path: &Path) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut BufReader::new(File::open(path)?), &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Read a manifest in the format written by `sha256sum`, mapping each path, made relative by
+/// stripping any leading `./` or `/`, to its lowercase hash
+pub(crate) fn read_manifest(path: &Path) -> io::Result<HashMap<PathBuf, String>> {
+    let mut hashes = HashMap::new();
+    for (number, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        // The hash is separated from the path by a space and a space or, in binary mode, a `*`
+        let Some((hash, file)) = line
+            .split_once("  ")
+            .or_else(|| line.split_once(" *"))
+            .filter(|(hash, _)| hash.len() == 64 && hash.chars().all(|c| c.is_ascii_hexdigit()))
+        else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}:{}: not a sha256sum line", path.display(), number + 1),
+            ));
+        };
+        let file = file.trim_start_matches("./").trim_start_matches('/');
+        hashes.insert(PathBuf::from(file), hash.to_lowercase());
+    }
+    Ok(hashes)
+}
+
+/// The hex encoded hash of the tree at `root`, covering the path, type and permission bits of
+/// every entry, the contents of files and the targets of symlinks. Entries are hashed in order of
+/// their paths, so the result does not depend on the order the filesystem lists them in.
+pub(crate) fn tree_hash(root: &Path, algo: HashAlgo) -> io::Result<String> {
+    match algo {
+        HashAlgo::Sha256 => hash_tree::<Sha256>(root),
+        HashAlgo::Sha512 => hash_tree::<Sha512>(root),
+    }
+}
+
+fn hash_tree<D: Digest + Write>(root: &Path) -> io::Result<String> {
+    let mut hasher = D::new();
+    for entry in WalkDir::new(root).sort_by_file_name() {
+        let entry = entry?;
+        let rel = entry.path().strip_prefix(root).unwrap_or(entry.path());
+        let metadata = entry.metadata()?;
+        // Each field is terminated, so different trees cannot run together into the same input
+        hasher.update(rel.as_os_str().as_encoded_bytes());
+        hasher.update([0]);
+        hasher.update(permission_bits(&metadata).to_le_bytes());
+        if metadata.is_file() {
+            hasher.update(b"f");
+            hasher.update(metadata.len().to_le_bytes());
+            io::copy(&mut File::open(entry.path())?, &mut hasher)?;
+        } else if metadata.is_symlink() {
+            hasher.update(b"l");
+            hasher.update(
+                std::fs::read_link(entry.path())?
+                    .as_os_str()
+                    .as_encoded_bytes(),
+            );
+            hasher.update([0]);
+        } else if metadata.is_dir() {
+            hasher.update(b"d");
+        } else {
+            hasher.update(b"s");
+        }
+    }
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+/// The permission bits of an entry, or just whether it is read-only where there are none
+fn permission_bits(metadata: &std::fs::Metadata) -> u32 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        metadata.permissions().mode() & 0o7777
+    }
+    #[cfg(not(unix))]
+    {
+        u32::from(metadata.permissions().readonly())
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    /// Build the same tree below `root`, creating its entries in the given order
+    fn build(tmp: &TempDir, root: &str, order: &[&str]) -> PathBuf {
+        for name in order {
+            match *name {
+                "link" => {
+                    std::fs::create_dir_all(tmp.join(root)).unwrap();
+                    std::os::unix::fs::symlink("lib/libc.so", tmp.join(root).join("link")).unwrap();
+                }
+                "dir" => std::fs::create_dir_all(tmp.join(root).join("dir")).unwrap(),
+                name => {
+                    tmp.write(format!("{}/{}", root, name), name);
+                }
+            }
+        }
+        tmp.join(root)
+    }
+
+    #[test]
+    fn tree_hash_ignores_creation_order() {
+        let tmp = TempDir::new();
+        let order = ["lib/libc.so", "bin/sh", "dir", "link", "etc/hosts"];
+        let a = build(&tmp, "a", &order);
+        let reversed: Vec<_> = order.iter().rev().copied().collect();
+        let b = build(&tmp, "b", &reversed);
+        for algo in [HashAlgo::Sha256, HashAlgo::Sha512] {
+            assert_eq!(tree_hash(&a, algo).unwrap(), tree_hash(&b, algo).unwrap());
+        }
+    }
+
+    #[test]
+    fn tree_hash_covers_modes_and_link_targets() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tmp = TempDir::new();
+        let root = build(&tmp, "root", &["lib/libc.so", "bin/sh", "link"]);
+        let original = tree_hash(&root, HashAlgo::Sha256).unwrap();
+
+        let sh = root.join("bin/sh");
+        let mode = sh.metadata().unwrap().permissions().mode();
+        std::fs::set_permissions(&sh, std::fs::Permissions::from_mode(mode ^ 0o100)).unwrap();
+        let chmodded = tree_hash(&root, HashAlgo::Sha256).unwrap();
+        assert_ne!(chmodded, original);
+        std::fs::set_permissions(&sh, std::fs::Permissions::from_mode(mode)).unwrap();
+        assert_eq!(tree_hash(&root, HashAlgo::Sha256).unwrap(), original);
+
+        std::fs::remove_file(root.join("link")).unwrap();
+        std::os::unix::fs::symlink("bin/sh", root.join("link")).unwrap();
+        assert_ne!(tree_hash(&root, HashAlgo::Sha256).unwrap(), original);
+    }
+}
@@ -0,0 +1,63 @@
+//! Compare the contents of files.
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+/// The size of the chunks compared at a time when streaming
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Determine if the contents of the files `a` and `b` differ, stopping at the first difference
+pub(crate) fn contents_differ(a: &Path, b: &Path) -> io::Result<bool> {
+    let (mut a, mut b) = (File::open(a)?, File::open(b)?);
+    let len = a.metadata()?.len();
+    if len != b.metadata()?.len() {
+        return Ok(true);
+    }
+    if len == 0 {
+        return Ok(false);
+    }
+    #[cfg(feature = "mmap")]
+    if let Ok(differ) = mmap_contents_differ(&a, &b) {
+        return Ok(differ);
+    }
+    streaming_contents_differ(&mut a, &mut b)
+}
+
+/// Compare two files by memory mapping both of them
+#[cfg(feature = "mmap")]
+fn mmap_contents_differ(a: &File, b: &File) -> io::Result<bool> {
+    // SAFETY: the maps are only read while comparing. If another process modifies the files
+    // meanwhile the comparison result is meaningless, just like a streaming comparison racing
+    // with a writer, but the maps stay valid.
+    let (a, b) = unsafe { (memmap2::Mmap::map(a)?, memmap2::Mmap::map(b)?) };
+    Ok(a[..] != b[..])
+}
+
+/// Compare two files by reading them in chunks
+fn streaming_contents_differ(a: &mut File, b: &mut File) -> io::Result<bool> {
+    let (mut buf_a, mut buf_b) = (vec![0; CHUNK_SIZE], vec![0; CHUNK_SIZE]);
+    loop {
+        let read_a = fill(a, &mut buf_a)?;
+        let read_b = fill(b, &mut buf_b)?;
+        if buf_a[..read_a] != buf_b[..read_b] {
+            return Ok(true);
+        }
+        if read_a == 0 {
+            return Ok(false);
+        }
+    }
+}
+
+/// Read from `reader` until `buf` is full or the end of the file, returning the bytes read
+fn fill(reader: &mut File, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(read)
+}
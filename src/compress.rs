@@ -0,0 +1,52 @@
+//! Compress files while copying them.
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// A compression format files can be copied in, see
+/// [`CopyBuilder::compress_matching`](crate::CopyBuilder::compress_matching)
+pub enum Compression {
+    /// gzip, written to `<name>.gz`
+    Gzip,
+    /// Zstandard, written to `<name>.zst`. Requires the `zstd` feature.
+    Zstd,
+}
+
+impl Compression {
+    /// The extension appended to the names of compressed files
+    pub fn extension(self) -> &'static str {
+        match self {
+            Compression::Gzip => "gz",
+            Compression::Zstd => "zst",
+        }
+    }
+}
+
+/// Write the contents of `src` to `dst` compressed with `compression`, returning the size of the
+/// compressed file
+pub(crate) fn compress_file(src: &Path, dst: &Path, compression: Compression) -> io::Result<u64> {
+    let mut reader = BufReader::new(File::open(src)?);
+    let writer = BufWriter::new(File::create(dst)?);
+    match compression {
+        Compression::Gzip => {
+            let mut encoder = flate2::write::GzEncoder::new(writer, flate2::Compression::default());
+            io::copy(&mut reader, &mut encoder)?;
+            encoder.finish()?.flush()?;
+        }
+        #[cfg(feature = "zstd")]
+        Compression::Zstd => {
+            let mut encoder = zstd::Encoder::new(writer, 0)?;
+            io::copy(&mut reader, &mut encoder)?;
+            encoder.finish()?.flush()?;
+        }
+        #[cfg(not(feature = "zstd"))]
+        Compression::Zstd => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Zstandard compression requires the zstd feature",
+            ))
+        }
+    }
+    Ok(dst.metadata()?.len())
+}
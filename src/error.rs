@@ -0,0 +1,261 @@
+//! The error type of copy operations.
+use std::fmt::{self, Display};
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+
+#[derive(Debug)]
+/// An error produced by a copy operation
+pub enum CopyError {
+    /// The source directory does not exist
+    SourceMissing(PathBuf),
+    /// The source directory has no entries, see
+    /// [`CopyBuilder::error_on_empty`](crate::CopyBuilder::error_on_empty)
+    SourceEmpty(PathBuf),
+    /// The destination is the source or inside it
+    DestinationInSource {
+        /// The source directory
+        source: PathBuf,
+        /// The destination directory
+        destination: PathBuf,
+    },
+    /// A symlink's target does not exist inside the source, see
+    /// [`CopyBuilder::dangling_symlinks`](crate::CopyBuilder::dangling_symlinks)
+    DanglingSymlink {
+        /// The symlink in the source
+        link: PathBuf,
+        /// The target it points to
+        target: PathBuf,
+    },
+    /// A symlink would point outside the directory it is in after being made relative, see
+    /// [`Relativizer::strict`](crate::Relativizer::strict)
+    EscapingSymlink {
+        /// The symlink
+        link: PathBuf,
+        /// Its relative target
+        target: PathBuf,
+    },
+    /// Two source files would be copied to the same destination when flattening or renaming, see
+    /// [`CopyBuilder::on_collision`](crate::CopyBuilder::on_collision)
+    Collision {
+        /// The destination file
+        destination: PathBuf,
+        /// The source file copied there first
+        first: PathBuf,
+        /// The source file that would overwrite it
+        second: PathBuf,
+    },
+    /// The destination is `/` or another protected directory, see
+    /// [`assert_safe_destination`](crate::assert_safe_destination)
+    UnsafeDestination(PathBuf),
+    /// The source contains a special file, see
+    /// [`CopyBuilder::special_files`](crate::CopyBuilder::special_files)
+    SpecialFile(PathBuf),
+    /// A limit on the amount of data copied was reached
+    LimitExceeded {
+        /// The limit that was exceeded
+        limit: Limit,
+        /// How much was copied before stopping, in the unit of the limit
+        copied: u64,
+    },
+    /// The copy took longer than allowed, see
+    /// [`CopyBuilder::with_timeout`](crate::CopyBuilder::with_timeout)
+    TimedOut {
+        /// How long the copy ran before stopping
+        elapsed: Duration,
+    },
+    /// The source does not match the expected manifest, see
+    /// [`CopyBuilder::verify_source_manifest`](crate::CopyBuilder::verify_source_manifest)
+    ManifestMismatch {
+        /// The manifest the source was checked against
+        manifest: PathBuf,
+        /// Source files whose hash differs from the manifest
+        changed: Vec<PathBuf>,
+        /// Files in the manifest that would not be copied from the source
+        missing: Vec<PathBuf>,
+        /// Source files that would be copied but are not in the manifest
+        unexpected: Vec<PathBuf>,
+    },
+    /// Two options of the builder cannot be combined, see
+    /// [`CopyBuilder::validate`](crate::CopyBuilder::validate)
+    ConflictingOptions {
+        /// The option set first
+        first: &'static str,
+        /// The option it conflicts with
+        second: &'static str,
+        /// Why they cannot be combined
+        reason: &'static str,
+    },
+    /// An entry could not be moved or linked into place because it is on a different
+    /// filesystem, e.g. the staging directory of an
+    /// [atomic](crate::CopyBuilder::atomic) copy
+    CrossDevice {
+        /// The entry that was to be moved or linked
+        src: PathBuf,
+        /// Where it was to be moved or linked to
+        dst: PathBuf,
+    },
+    /// Any other I/O error
+    Io(io::Error),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// A limit on the amount of data a copy operation may copy
+pub enum Limit {
+    /// The total size in bytes of copied files
+    TotalBytes(u64),
+    /// The number of copied files
+    Files(u64),
+}
+
+impl Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Limit::TotalBytes(bytes) => write!(f, "{} bytes", bytes),
+            Limit::Files(files) => write!(f, "{} files", files),
+        }
+    }
+}
+
+impl Display for CopyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CopyError::SourceMissing(path) => {
+                write!(f, "Source directory {} does not exist", path.display())
+            }
+            CopyError::SourceEmpty(path) => {
+                write!(f, "Source directory {} is empty", path.display())
+            }
+            CopyError::DestinationInSource {
+                source,
+                destination,
+            } => write!(
+                f,
+                "Destination {} is the source {} or inside it",
+                destination.display(),
+                source.display()
+            ),
+            CopyError::DanglingSymlink { link, target } => write!(
+                f,
+                "Symlink {} points to {}, which does not exist in the source",
+                link.display(),
+                target.display()
+            ),
+            CopyError::EscapingSymlink { link, target } => write!(
+                f,
+                "Symlink {} would point to {}, which is outside the sysroot",
+                link.display(),
+                target.display()
+            ),
+            CopyError::Collision {
+                destination,
+                first,
+                second,
+            } => write!(
+                f,
+                "Both {} and {} would be copied to {}",
+                first.display(),
+                second.display(),
+                destination.display()
+            ),
+            CopyError::UnsafeDestination(path) => write!(
+                f,
+                "Refusing to modify the symlinks in {}, which is protected",
+                path.display()
+            ),
+            CopyError::SpecialFile(path) => write!(
+                f,
+                "{} is a special file, such as a socket, and cannot be copied",
+                path.display()
+            ),
+            CopyError::LimitExceeded { limit, copied } => {
+                let unit = match limit {
+                    Limit::TotalBytes(_) => "bytes",
+                    Limit::Files(_) => "files",
+                };
+                write!(
+                    f,
+                    "Copy limit of {} exceeded after copying {} {}",
+                    limit, copied, unit
+                )
+            }
+            CopyError::TimedOut { elapsed } => {
+                write!(f, "Copy timed out after {:.1}s", elapsed.as_secs_f64())
+            }
+            CopyError::ManifestMismatch {
+                manifest,
+                changed,
+                missing,
+                unexpected,
+            } => {
+                write!(
+                    f,
+                    "Source does not match manifest {}: {} changed, {} missing, {} unexpected",
+                    manifest.display(),
+                    changed.len(),
+                    missing.len(),
+                    unexpected.len()
+                )?;
+                for (kind, paths) in [
+                    ("changed", changed),
+                    ("missing", missing),
+                    ("unexpected", unexpected),
+                ] {
+                    for path in paths {
+                        write!(f, "\n  {}: {}", kind, path.display())?;
+                    }
+                }
+                Ok(())
+            }
+            CopyError::ConflictingOptions {
+                first,
+                second,
+                reason,
+            } => write!(
+                f,
+                "{} cannot be combined with {}: {}",
+                first, second, reason
+            ),
+            CopyError::CrossDevice { src, dst } => write!(
+                f,
+                "Cannot move {} to {}, which is on a different filesystem",
+                src.display(),
+                dst.display()
+            ),
+            CopyError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for CopyError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CopyError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for CopyError {
+    fn from(e: io::Error) -> Self {
+        CopyError::Io(e)
+    }
+}
+
+impl From<CopyError> for io::Error {
+    fn from(e: CopyError) -> Self {
+        match e {
+            CopyError::Io(e) => e,
+            CopyError::SourceMissing(_) => io::Error::new(io::ErrorKind::NotFound, e),
+            CopyError::TimedOut { .. } => io::Error::new(io::ErrorKind::TimedOut, e),
+            CopyError::CrossDevice { .. } => io::Error::new(io::ErrorKind::CrossesDevices, e),
+            CopyError::ManifestMismatch { .. } => io::Error::new(io::ErrorKind::InvalidData, e),
+            CopyError::DestinationInSource { .. }
+            | CopyError::UnsafeDestination(_)
+            | CopyError::ConflictingOptions { .. } => {
+                io::Error::new(io::ErrorKind::InvalidInput, e)
+            }
+            e => io::Error::other(e),
+        }
+    }
+}
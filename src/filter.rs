@@ -0,0 +1,300 @@
+//! Include and exclude rules selecting which entries of the source are copied.
+use crate::SkipReason;
+use globset::{GlobBuilder, GlobMatcher};
+use regex::{Regex, RegexBuilder};
+use std::fmt::{self, Display};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+/// How an entry matched by both include and exclude rules is handled
+pub enum Precedence {
+    /// An entry matching any exclude rule is not copied. If there are include rules, an entry
+    /// must also match one of them (and one of each kind, paths and filters) to be copied.
+    #[default]
+    ExcludeWins,
+    /// Like `ExcludeWins`, but an entry matching any include rule is copied even if it also
+    /// matches an exclude rule
+    IncludeWins,
+    /// The last rule, in the order they were added, matching an entry decides whether it is
+    /// copied, like gitignore. Entries no rule matches are copied only if there are no include
+    /// rules.
+    LastMatchWins,
+}
+
+#[derive(Debug, Clone)]
+/// A single include or exclude rule
+pub(crate) struct Rule {
+    include: bool,
+    matcher: Matcher,
+}
+
+#[derive(Debug, Clone)]
+/// What a rule matches
+pub(crate) enum Matcher {
+    /// Any entry at or below this path
+    Path(PathBuf),
+    /// Any file whose path contains this string
+    Substring(String),
+    /// Any file whose path matches this regex somewhere, compiled both as is and ignoring case
+    Regex { exact: Regex, folded: Regex },
+    /// Any entry whose path relative to the root matches this glob, compiled both as is and
+    /// ignoring case
+    Glob {
+        exact: GlobMatcher,
+        folded: GlobMatcher,
+    },
+    /// Any entry below the root with a path component of exactly this name, and everything below
+    /// it
+    Component(String),
+}
+
+#[derive(Debug, Clone, Default)]
+/// An ordered list of rules and how to resolve conflicts between them
+pub(crate) struct Filters {
+    rules: Vec<Rule>,
+    precedence: Precedence,
+    /// Match substrings, regexes and globs regardless of case
+    case_insensitive: bool,
+}
+
+impl Matcher {
+    /// A matcher for the glob `pattern`
+    pub(crate) fn glob(pattern: &str) -> Result<Matcher, globset::Error> {
+        Ok(Matcher::Glob {
+            exact: GlobBuilder::new(pattern).build()?.compile_matcher(),
+            folded: GlobBuilder::new(pattern)
+                .case_insensitive(true)
+                .build()?
+                .compile_matcher(),
+        })
+    }
+
+    /// A matcher for the regex `re`
+    pub(crate) fn regex(re: &str) -> Result<Matcher, regex::Error> {
+        Ok(Matcher::Regex {
+            exact: Regex::new(re)?,
+            folded: RegexBuilder::new(re).case_insensitive(true).build()?,
+        })
+    }
+
+    /// Determine if the entry at `path` below `root` is matched, ignoring the case of
+    /// substrings, regexes and globs if `fold_case` is set
+    fn matches(&self, root: &Path, path: &Path, is_dir: bool, fold_case: bool) -> bool {
+        match self {
+            Matcher::Path(p) => path.starts_with(p),
+            Matcher::Substring(s) if fold_case => {
+                !is_dir
+                    && path
+                        .to_string_lossy()
+                        .to_lowercase()
+                        .contains(&s.to_lowercase())
+            }
+            Matcher::Substring(s) => !is_dir && path.to_string_lossy().contains(s.as_str()),
+            Matcher::Regex { folded, .. } if fold_case => {
+                !is_dir && folded.is_match(&path.to_string_lossy())
+            }
+            Matcher::Regex { exact, .. } => !is_dir && exact.is_match(&path.to_string_lossy()),
+            Matcher::Glob { exact, folded } => path.strip_prefix(root).is_ok_and(|rel| {
+                if fold_case {
+                    folded.is_match(rel)
+                } else {
+                    exact.is_match(rel)
+                }
+            }),
+            Matcher::Component(name) => path.strip_prefix(root).is_ok_and(|rel| {
+                rel.components()
+                    .any(|component| component.as_os_str() == name.as_str())
+            }),
+        }
+    }
+}
+
+impl Display for Matcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Matcher::Path(p) => write!(f, "{}", p.display()),
+            Matcher::Substring(s) => write!(f, "{}", s),
+            Matcher::Regex { exact, .. } => write!(f, "{}", exact),
+            Matcher::Glob { exact, .. } => write!(f, "{}", exact.glob()),
+            Matcher::Component(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+impl Filters {
+    /// Add a rule after all existing ones
+    pub(crate) fn push(&mut self, include: bool, matcher: Matcher) {
+        self.rules.push(Rule { include, matcher });
+    }
+
+    /// Add a rule before all existing ones
+    pub(crate) fn prepend(&mut self, include: bool, matcher: Matcher) {
+        self.rules.insert(0, Rule { include, matcher });
+    }
+
+    pub(crate) fn set_precedence(&mut self, precedence: Precedence) {
+        self.precedence = precedence;
+    }
+
+    pub(crate) fn set_case_insensitive(&mut self, case_insensitive: bool) {
+        self.case_insensitive = case_insensitive;
+    }
+
+    /// Determine if `matcher` matches the entry at `path` below `root`
+    pub(crate) fn matches(
+        &self,
+        matcher: &Matcher,
+        root: &Path,
+        path: &Path,
+        is_dir: bool,
+    ) -> bool {
+        matcher.matches(root, path, is_dir, self.case_insensitive)
+    }
+
+    /// The include rules, in order
+    pub(crate) fn includes(&self) -> impl Iterator<Item = &Matcher> {
+        self.rules
+            .iter()
+            .filter(|rule| rule.include)
+            .map(|rule| &rule.matcher)
+    }
+
+    /// Determine if the entry at `path` below `root` is selected for copying
+    pub(crate) fn selects(&self, root: &Path, path: &Path, is_dir: bool) -> bool {
+        let included = || {
+            self.rules
+                .iter()
+                .any(|rule| rule.include && self.matches(&rule.matcher, root, path, is_dir))
+        };
+        let excluded = || {
+            self.rules
+                .iter()
+                .any(|rule| !rule.include && self.matches(&rule.matcher, root, path, is_dir))
+        };
+        match self.precedence {
+            Precedence::ExcludeWins => self.in_scope(root, path, is_dir) && !excluded(),
+            Precedence::IncludeWins => {
+                self.in_scope(root, path, is_dir) && (included() || !excluded())
+            }
+            Precedence::LastMatchWins => {
+                match self
+                    .rules
+                    .iter()
+                    .rev()
+                    .find(|rule| self.matches(&rule.matcher, root, path, is_dir))
+                {
+                    Some(rule) => rule.include,
+                    None => self.is_include_ancestor(path, is_dir) || self.includes().count() == 0,
+                }
+            }
+        }
+    }
+
+    /// Why the entry at `path` below `root`, which is not selected, is skipped
+    pub(crate) fn rejection(&self, root: &Path, path: &Path, is_dir: bool) -> SkipReason {
+        let excluded = self
+            .rules
+            .iter()
+            .any(|rule| !rule.include && self.matches(&rule.matcher, root, path, is_dir));
+        match excluded {
+            true => SkipReason::Excluded,
+            false => SkipReason::NotIncluded,
+        }
+    }
+
+    /// Determine if a directory that is not selected itself still has to be walked, because an
+    /// include rule may select something below it
+    pub(crate) fn may_select_below(&self, dir: &Path) -> bool {
+        self.precedence != Precedence::ExcludeWins
+            && self.includes().any(|matcher| match matcher {
+                Matcher::Path(p) => p.starts_with(dir) || dir.starts_with(p),
+                Matcher::Substring(_)
+                | Matcher::Regex { .. }
+                | Matcher::Glob { .. }
+                | Matcher::Component(_) => true,
+            })
+    }
+
+    /// Determine if `path` satisfies the include rules of each kind, if there are any
+    fn in_scope(&self, root: &Path, path: &Path, is_dir: bool) -> bool {
+        let (mut has_paths, mut in_paths) = (false, false);
+        let (mut has_substrings, mut in_substrings) = (false, false);
+        for matcher in self.includes() {
+            match matcher {
+                Matcher::Path(p) => {
+                    has_paths = true;
+                    in_paths |= path.starts_with(p) || p.starts_with(path);
+                }
+                Matcher::Substring(_)
+                | Matcher::Regex { .. }
+                | Matcher::Glob { .. }
+                | Matcher::Component(_) => {
+                    has_substrings = true;
+                    in_substrings |= self.matches(matcher, root, path, is_dir);
+                }
+            }
+        }
+        (!has_paths || in_paths) && (is_dir || !has_substrings || in_substrings)
+    }
+
+    /// Determine if the directory `path` leads to an include path and so must be created
+    fn is_include_ancestor(&self, path: &Path, is_dir: bool) -> bool {
+        is_dir
+            && self.includes().any(|matcher| match matcher {
+                Matcher::Path(p) => p.starts_with(path),
+                _ => false,
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filters(include: bool, matcher: Matcher, case_insensitive: bool) -> Filters {
+        let mut filters = Filters::default();
+        filters.push(include, matcher);
+        filters.set_case_insensitive(case_insensitive);
+        filters
+    }
+
+    #[test]
+    fn case_insensitive_glob_exclude() {
+        let root = Path::new("/src");
+        let path = Path::new("/src/doc/readme");
+        let glob = || Matcher::glob("**/README").unwrap();
+        assert!(filters(false, glob(), false).selects(root, path, false));
+        assert!(!filters(false, glob(), true).selects(root, path, false));
+    }
+
+    #[test]
+    fn case_insensitive_glob_include() {
+        let root = Path::new("/src");
+        let path = Path::new("/src/doc/readme");
+        let glob = || Matcher::glob("**/README").unwrap();
+        assert!(!filters(true, glob(), false).selects(root, path, false));
+        assert!(filters(true, glob(), true).selects(root, path, false));
+    }
+
+    #[test]
+    fn case_insensitive_substring() {
+        let root = Path::new("/src");
+        let path = Path::new("/src/doc/readme");
+        let substring = || Matcher::Substring("README".to_owned());
+        assert!(filters(false, substring(), false).selects(root, path, false));
+        assert!(!filters(false, substring(), true).selects(root, path, false));
+        assert!(!filters(true, substring(), false).selects(root, path, false));
+        assert!(filters(true, substring(), true).selects(root, path, false));
+    }
+
+    #[test]
+    fn case_insensitive_regex() {
+        let root = Path::new("/src");
+        let path = Path::new("/src/doc/readme");
+        let regex = || Matcher::regex("/README$").unwrap();
+        assert!(filters(false, regex(), false).selects(root, path, false));
+        assert!(!filters(false, regex(), true).selects(root, path, false));
+        assert!(!filters(true, regex(), false).selects(root, path, false));
+        assert!(filters(true, regex(), true).selects(root, path, false));
+    }
+}
@@ -0,0 +1,58 @@
+//! Copy the inode flags set by `chattr(1)` on Linux.
+use std::io;
+use std::path::Path;
+
+/// The immutable flag, `chattr +i`
+#[cfg(target_os = "linux")]
+const FS_IMMUTABLE_FL: libc::c_int = 0x10;
+/// The append-only flag, `chattr +a`
+#[cfg(target_os = "linux")]
+const FS_APPEND_FL: libc::c_int = 0x20;
+
+/// Apply the immutable and append-only flags of `src` to `dst`. Does nothing on platforms other
+/// than Linux, or if `src` has neither flag.
+///
+/// Setting either flag requires `CAP_LINUX_IMMUTABLE`, otherwise this fails with a permission
+/// error.
+pub(crate) fn copy_inode_flags(src: &Path, dst: &Path) -> io::Result<()> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::fd::AsRawFd;
+
+        const COPIED_FLAGS: libc::c_int = FS_IMMUTABLE_FL | FS_APPEND_FL;
+        let src = std::fs::File::open(src)?;
+        let src_flags = match get_flags(src.as_raw_fd()) {
+            Ok(flags) => flags,
+            // The source's filesystem has no inode flags
+            Err(e) if matches!(e.raw_os_error(), Some(libc::ENOTTY | libc::EOPNOTSUPP)) => {
+                return Ok(())
+            }
+            Err(e) => return Err(e),
+        };
+        if src_flags & COPIED_FLAGS == 0 {
+            return Ok(());
+        }
+        let dst = std::fs::File::open(dst)?;
+        let dst_flags = get_flags(dst.as_raw_fd())?;
+        let flags = (dst_flags & !COPIED_FLAGS) | (src_flags & COPIED_FLAGS);
+        // SAFETY: the file descriptor is valid for the lifetime of `dst`, and the ioctl only
+        // reads the flags from the pointer
+        if unsafe { libc::ioctl(dst.as_raw_fd(), libc::FS_IOC_SETFLAGS, &flags) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = (src, dst);
+    Ok(())
+}
+
+/// Read the inode flags of the open file `fd`
+#[cfg(target_os = "linux")]
+fn get_flags(fd: std::os::fd::RawFd) -> io::Result<libc::c_int> {
+    let mut flags: libc::c_int = 0;
+    // SAFETY: `fd` is a valid file descriptor, and the ioctl writes a single int to the pointer
+    if unsafe { libc::ioctl(fd, libc::FS_IOC_GETFLAGS, &mut flags) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(flags)
+}
@@ -1,10 +1,68 @@
 //! Recursively copy a directory from a to b.
+use filetime::FileTime;
+use globset::{Glob, GlobMatcher, GlobSetBuilder};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
 use log::*;
-use std::fs::{copy, read_link};
-use std::io::{Error, ErrorKind};
+use same_file::Handle;
+use std::cell::Cell;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs::read_link;
+use std::io::{BufWriter, Error, ErrorKind, Write};
 use std::path::{Path, PathBuf};
-use std::time::SystemTime;
-use walkdir::WalkDir;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use walkdir::{DirEntry, WalkDir};
+
+mod backend;
+mod callback;
+mod checksum;
+mod compare;
+mod compress;
+mod error;
+mod filter;
+mod flags;
+pub mod mode;
+mod pool;
+mod relativize;
+mod resolve;
+mod semaphore;
+mod space;
+mod sysroot;
+#[cfg(test)]
+mod testutil;
+
+pub use backend::{CopyBackend, StdBackend};
+use callback::Callback;
+pub use checksum::HashAlgo;
+pub use compress::Compression;
+pub use error::{CopyError, Limit};
+pub use filter::Precedence;
+use filter::{Filters, Matcher};
+use pool::Pool;
+pub use relativize::{
+    assert_safe_destination, find_absolute_symlinks, relativize_symlinks, RelativizeProgress,
+    RelativizeStats, Relativizer,
+};
+use resolve::resolve_in_root;
+use semaphore::Semaphore;
+pub use space::available_space;
+pub use sysroot::{build_sysroot, BuildOptions, BuildReport, LinkStats};
+
+/// How many external symlinks are warned about individually, see [`CopyStats::external_symlinks`]
+const EXTERNAL_SYMLINK_WARNINGS: usize = 10;
+
+/// The names excluded by [`CopyBuilder::with_vcs_excludes`]
+pub const VCS_EXCLUDES: &[&str] = &[
+    ".git",
+    ".svn",
+    ".hg",
+    ".bzr",
+    "CVS",
+    "node_modules",
+    "target",
+    "__pycache__",
+];
 
 #[derive(Debug, Clone)]
 /// Recursively copy a directory from a to b.
@@ -19,14 +77,574 @@ pub struct CopyBuilder {
     overwrite_if_newer: bool,
     /// Overwrite target files if they differ in size
     overwrite_if_size_differs: bool,
-    /// A list of include filters
-    exclude_filters: Vec<String>,
-    /// A list of exclude filters
-    include_filters: Vec<String>,
-    /// A list of paths to exclude
-    exclude_paths: Vec<String>,
-    /// A list of paths to include
-    include_paths: Vec<String>,
+    /// Overwrite target files if their contents differ
+    overwrite_if_content_differs: bool,
+    /// Never overwrite target files that are newer than the source
+    protect_newer_destination: bool,
+    /// Make read-only target files writable before overwriting them
+    force_overwrite_readonly: bool,
+    /// The include and exclude paths and filters, in the order they were added
+    filters: Filters,
+    /// The name of a gitignore style file at the source root to apply
+    auto_ignore_file: Option<String>,
+    /// Fail if the source directory is empty
+    error_on_empty: bool,
+    /// How to handle symlinks whose target does not exist
+    dangling_symlinks: DanglingMode,
+    /// Skip symlinks resolving to entries that are filtered out
+    prune_symlinks_to_excluded: bool,
+    /// Rewrite relative symlink targets for the destination layout
+    normalize_relative_symlinks: bool,
+    /// How to handle sockets, FIFOs and device nodes
+    special_files: SpecialFiles,
+    /// Visit entries in file name order
+    sorted: bool,
+    /// Do not descend into filesystems mounted below the source
+    same_filesystem: bool,
+    /// Fail once copying would exceed this many bytes
+    max_total_bytes: Option<u64>,
+    /// Fail once copying would exceed this many files
+    max_files: Option<u64>,
+    /// Abort the copy once it has taken this long
+    timeout: Option<Duration>,
+    /// Set the times of everything copied to this fixed value
+    fixed_mtime: Option<SystemTime>,
+    /// Give everything copied the times of its source
+    preserve_timestamps: bool,
+    /// Only copy files modified at or after this time
+    modified_since: Option<SystemTime>,
+    /// The sizes in bytes of the largest and smallest regular files copied, if limited
+    max_file_size: Option<u64>,
+    min_file_size: Option<u64>,
+    /// Evict copied files from the page cache
+    bypass_page_cache: bool,
+    /// What to fsync
+    sync: SyncMode,
+    /// Hash the destination tree once copied
+    tree_hash: Option<HashAlgo>,
+    /// Carry on copying the remaining entries when one fails
+    continue_on_error: bool,
+    /// How many threads copy entries
+    threads: usize,
+    /// Only decide what to copy without writing anything
+    dry_run: bool,
+    /// Copy the immutable and append-only inode flags of files
+    preserve_inode_flags: bool,
+    /// Give copied files and created directories the setuid, setgid and sticky bits of their
+    /// source
+    preserve_special_bits: bool,
+    /// Give copied files and created directories the permissions of their source
+    preserve_permissions: bool,
+    /// Hardlink later copies of a source file with several links to its first copy
+    preserve_hardlinks: bool,
+    /// Copy what symlinks point to instead of the symlinks
+    follow_symlinks: bool,
+    /// The deepest level below the source copied, if limited
+    max_depth: Option<usize>,
+    /// Whether to clone files instead of copying them
+    reflink: ReflinkMode,
+    /// The size of the buffer files are copied through, if not left to the backend
+    buffer_size: Option<usize>,
+    /// Permission bits added to every copied file and created directory
+    min_mode: Option<(u32, u32)>,
+    /// The only types of entries copied, if set
+    allowed_types: Option<Vec<FileTypeKind>>,
+    /// Give the destination directory the permissions of the source directory
+    copy_root_metadata: bool,
+    /// Skip destination files with the size and modification time of their source
+    resume: bool,
+    /// Called for every source entry that is not copied
+    on_skip: Option<Callback<SkipFn>>,
+    /// Asked before replacing an existing destination file
+    on_overwrite: Option<Callback<OverwriteFn>>,
+    /// Called as files are copied
+    on_progress: Option<Callback<ProgressFn>>,
+    /// Give copies the owner and group of their source
+    preserve_ownership: bool,
+    /// Translates source user IDs when preserving ownership
+    uid_map: Option<Callback<IdMapFn>>,
+    /// Translates source group IDs when preserving ownership
+    gid_map: Option<Callback<IdMapFn>>,
+    /// Delete destination entries that are not present in the source
+    delete_extraneous: bool,
+    /// A list of destination paths or globs mirror mode never deletes
+    mirror_protect: Vec<String>,
+    /// Only copy what differs from this directory, and mark what is missing from the source
+    overlay_base: Option<PathBuf>,
+    /// Source relative path prefixes relocated to another destination relative prefix
+    remaps: Vec<(PathBuf, PathBuf)>,
+    /// Maps source relative paths to destination relative paths, or `None` to skip them
+    rename: Option<Callback<RenameFn>>,
+    /// Globs of source relative paths of files to compress, and how
+    compress: Vec<(String, Compression)>,
+    /// Source relative directories to walk instead of the whole source
+    roots: Vec<PathBuf>,
+    /// Copy every file directly into the destination root
+    flatten: bool,
+    /// How to handle files with the same name when flattening
+    on_collision: Collision,
+    /// A file to record the source of every destination entry in
+    origin_manifest: Option<PathBuf>,
+    /// A `sha256sum` manifest the source files must match before anything is copied
+    source_manifest: Option<PathBuf>,
+    /// Copy into a staging directory and rename it to the destination once complete
+    atomic: bool,
+    /// The directory to create the staging directory in, instead of next to the destination
+    temp_dir: Option<PathBuf>,
+    /// Limits how many files are open at once while copying
+    open_files: Arc<Semaphore>,
+    /// The backend performing the actual writes
+    backend: Arc<dyn CopyBackend>,
+}
+
+/// The type of [`CopyBuilder::with_skip_callback`] callbacks
+type SkipFn = dyn Fn(&Path, SkipReason) + Send + Sync;
+
+/// The type of [`CopyBuilder::with_overwrite_callback`] callbacks
+type OverwriteFn = dyn Fn(&Path, &Path) -> bool + Send + Sync;
+
+/// The type of [`CopyBuilder::on_progress`] callbacks
+type ProgressFn = dyn Fn(&Path, u64, u64) + Send + Sync;
+
+/// The type of [`CopyBuilder::with_rename`] callbacks
+type RenameFn = dyn Fn(&Path) -> Option<PathBuf> + Send + Sync;
+
+/// The type of [`CopyBuilder::with_uid_map`] and [`CopyBuilder::with_gid_map`] callbacks
+type IdMapFn = dyn Fn(u32) -> u32 + Send + Sync;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+/// Statistics about a completed copy operation
+pub struct CopyStats {
+    /// Regular files copied
+    pub files_copied: u64,
+    /// Symlinks created
+    pub symlinks_created: u64,
+    /// Directories created
+    pub dirs_created: u64,
+    /// Total size in bytes of the files copied
+    pub bytes_copied: u64,
+    /// Source entries left alone for any reason, like being up to date, filtered out or
+    /// protected. A filtered out directory counts once, not for every entry below it.
+    pub files_skipped: u64,
+    /// Time the copy operation took
+    pub elapsed: Duration,
+    /// Files hardlinked to an earlier copy of the same source file instead of being copied, see
+    /// [`CopyBuilder::preserve_hardlinks`]
+    pub hardlinks: u64,
+    /// Total size in bytes of the files hardlinked instead of copied
+    pub reclaimed_bytes: u64,
+    /// Destination files left alone because they were newer than the source
+    pub protected_newer: u64,
+    /// Destination entries deleted because they are not present in the source
+    pub deleted: u64,
+    /// Whiteout markers created for entries of the overlay base missing from the source, see
+    /// [`CopyBuilder::overlay_against`]
+    pub whiteouts: u64,
+    /// Source entries that could not be read while walking the source, such as directories
+    /// without read permission. Everything below them is missing from the destination.
+    pub walk_errors: u64,
+    /// Source entries skipped because their type is not allowed, see
+    /// [`CopyBuilder::with_allowed_types`]
+    pub disallowed: u64,
+    /// Copied symlinks pointing outside the source, with their targets: absolute targets that do
+    /// not exist inside the source, and relative targets climbing out of it. These dangle in the
+    /// destination once it is relativized or used as a sysroot.
+    pub external_symlinks: Vec<(PathBuf, PathBuf)>,
+    /// Include paths and filters that did not match any entry in the source
+    pub unmatched_includes: Vec<String>,
+    /// Copied files and bytes per file extension. Files without an extension are counted under
+    /// `(none)`.
+    pub by_extension: BTreeMap<String, ExtensionStats>,
+    /// The hex encoded hash of the destination tree, see [`CopyBuilder::with_tree_hash`]
+    pub tree_hash: Option<String>,
+}
+
+impl CopyStats {
+    /// The average number of bytes copied per second
+    pub fn bytes_per_sec(&self) -> f64 {
+        match self.elapsed.as_secs_f64() {
+            secs if secs > 0.0 => self.bytes_copied as f64 / secs,
+            _ => 0.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+/// The files of a single extension copied by a copy operation
+pub struct ExtensionStats {
+    /// Number of files copied
+    pub files: u64,
+    /// Total size in bytes of the files copied
+    pub bytes: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+/// The work a copy operation would do, as computed by [`CopyBuilder::estimate`]
+pub struct CopyEstimate {
+    /// Regular files that would be copied
+    pub files: u64,
+    /// Symlinks that would be created
+    pub symlinks: u64,
+    /// Total size in bytes of the files that would be copied
+    pub total_bytes: u64,
+    /// Total size in bytes of the destination files they would replace
+    pub replaced_bytes: u64,
+}
+
+impl CopyEstimate {
+    /// How many bytes the destination would grow by, as replaced files free up their space
+    pub fn required_bytes(&self) -> u64 {
+        self.total_bytes.saturating_sub(self.replaced_bytes)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// A single operation of a copy, as yielded by [`CopyBuilder::plan`]
+pub enum CopyOp {
+    /// Copy the regular file `source` of `len` bytes to `destination`
+    CopyFile {
+        source: PathBuf,
+        destination: PathBuf,
+        len: u64,
+    },
+    /// Hardlink `destination` to `target`, an earlier copy of `source`, see
+    /// [`CopyBuilder::preserve_hardlinks`]
+    Hardlink {
+        source: PathBuf,
+        destination: PathBuf,
+        target: PathBuf,
+    },
+    /// Create a symlink at `destination` pointing to `target`, recreating `source`
+    Symlink {
+        source: PathBuf,
+        destination: PathBuf,
+        target: PathBuf,
+    },
+    /// Create the directory `destination`
+    MakeDir { destination: PathBuf },
+    /// Recreate the special file `source` at `destination`
+    Special {
+        source: PathBuf,
+        destination: PathBuf,
+    },
+    /// Leave `source` alone, because it is already up to date, protected or cannot be copied
+    Skip { source: PathBuf },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// An entry that differs or would change between the source and the destination, as computed by
+/// [`CopyBuilder::diff`]
+pub struct DiffEntry {
+    /// The path of the entry relative to the destination
+    pub path: PathBuf,
+    /// What a copy would do to it
+    pub change: Change,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// What a copy would do to an entry of the destination, see [`CopyBuilder::diff`]
+pub enum Change {
+    /// It is missing and would be created
+    Added,
+    /// It exists and would be overwritten
+    Updated(UpdateReason),
+    /// It exists and would be left alone
+    Unchanged,
+    /// It is not present in the source and would be deleted in mirror mode, see
+    /// [`CopyBuilder::delete_extraneous`]
+    Removed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Why an existing destination entry would be overwritten, see [`CopyBuilder::diff`]
+pub enum UpdateReason {
+    /// The source is newer, see [`CopyBuilder::overwrite_if_newer`]
+    Newer,
+    /// The sizes differ, see [`CopyBuilder::overwrite_if_size_differs`]
+    Size,
+    /// The contents differ, see [`CopyBuilder::overwrite_if_content_differs`]
+    Content,
+    /// Everything is overwritten, see [`CopyBuilder::overwrite`] and [`CopyBuilder::resume`]
+    Overwrite,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// What was done with a single entry of the source, see [`CopyBuilder::copy_one`]
+pub enum CopyDecision {
+    /// The regular file was copied, writing this many bytes
+    Copied(u64),
+    /// The regular file was hardlinked to an earlier copy of it, saving this many bytes, see
+    /// [`CopyBuilder::preserve_hardlinks`]
+    Hardlinked(u64),
+    /// The symlink was recreated
+    Symlinked,
+    /// The directory was created
+    CreatedDir,
+    /// The special file was recreated
+    Recreated,
+    /// Nothing was done, for a reason worth reporting if set
+    Skipped(Option<SkipReason>),
+}
+
+/// A decision carried out by [`CopyBuilder::apply_entry`]
+struct Applied {
+    entry: DirEntry,
+    dest_entry: PathBuf,
+    /// Where the contents of the entry came from, for the origin manifest
+    origin: PathBuf,
+    /// The size of the file, if it was to be copied
+    len: Option<u64>,
+    done: Result<CopyDecision, CopyError>,
+}
+
+/// A walk of a directory in the source, yielding each entry with its path in the source, see
+/// [`CopyBuilder::walk`]
+type SourceWalk<'a> = Box<dyn Iterator<Item = walkdir::Result<(PathBuf, DirEntry)>> + 'a>;
+
+#[derive(Debug, Default)]
+/// Counts kept while walking the source, shared with the walk's filters
+struct WalkCounts {
+    /// Entries that could not be read
+    errors: Cell<u64>,
+    /// Entries pruned by the filters, along with everything below them
+    pruned: Cell<u64>,
+}
+
+#[derive(Default)]
+/// What a copy has done so far, see [`CopyBuilder::finish`]
+struct RunState {
+    stats: CopyStats,
+    failures: Vec<(PathBuf, CopyError)>,
+    /// The directories entries were written to, to sync at the end
+    written_dirs: BTreeSet<PathBuf>,
+    /// Directories to set the times of at the end, with their source
+    dirs: Vec<(PathBuf, PathBuf)>,
+    /// Created directories to give the permissions of their source at the end, with the source
+    dir_permissions: Vec<(PathBuf, PathBuf)>,
+    files_copied: u64,
+    manifest: Option<BufWriter<std::fs::File>>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+/// How to handle source symlinks whose target does not exist inside the source
+pub enum DanglingMode {
+    /// Recreate the symlink verbatim. Dangling links are normal in a sysroot, where they often
+    /// point at paths only populated at runtime.
+    #[default]
+    Keep,
+    /// Do not copy the symlink
+    Skip,
+    /// Fail with [`CopyError::DanglingSymlink`]
+    Error,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+/// How much of the copy to flush to disk before returning, see [`CopyBuilder::sync`]
+pub enum SyncMode {
+    /// Leave flushing to the operating system
+    #[default]
+    None,
+    /// fsync every copied file once it is written
+    Files,
+    /// Also fsync every directory entries were written to once the copy is complete
+    Full,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+/// Whether to clone files instead of copying their contents, see [`CopyBuilder::reflink`]
+pub enum ReflinkMode {
+    /// Always copy the contents
+    #[default]
+    Never,
+    /// Clone files where the filesystem supports it, copying the contents elsewhere
+    Auto,
+    /// Clone every file, failing where the filesystem does not support it
+    Always,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+/// How to handle special files in the source, i.e. sockets, FIFOs and device nodes
+pub enum SpecialFiles {
+    /// Do not copy them, printing a message for each
+    #[default]
+    Skip,
+    /// Recreate them in the destination with `mknod`. Sockets are recreated unbound, and device
+    /// nodes need the privilege to create them.
+    Recreate,
+    /// Fail with [`CopyError::SpecialFile`]
+    Error,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+/// Why an entry of the source was not copied, see [`CopyBuilder::with_skip_callback`]
+pub enum SkipReason {
+    /// It matches an exclude path or filter
+    Excluded,
+    /// There are include paths or filters, and it does not match them
+    NotIncluded,
+    /// It is ignored by the ignore file
+    Ignored,
+    /// It is on another filesystem than the source, see [`CopyBuilder::same_filesystem`]
+    OtherFilesystem,
+    /// The destination already exists and the overwrite options consider it up to date
+    UpToDate,
+    /// The destination is newer than the source, see
+    /// [`CopyBuilder::protect_newer_destination`]
+    ProtectedNewer,
+    /// It was not modified since the cutoff, see [`CopyBuilder::with_modified_since`]
+    NotModifiedSince,
+    /// It is a regular file larger than the maximum or smaller than the minimum size, see
+    /// [`CopyBuilder::max_file_size`]
+    OutsideSizeLimits,
+    /// It is a special file, see [`CopyBuilder::special_files`]
+    UnsupportedType,
+    /// Its type is not allowed, see [`CopyBuilder::with_allowed_types`]
+    DisallowedType,
+    /// It is a symlink whose target does not exist in the source, see
+    /// [`CopyBuilder::dangling_symlinks`]
+    Dangling,
+    /// It is a symlink that does not resolve to a regular file when flattening
+    NotAFile,
+    /// An earlier file took its name when flattening or renaming, see
+    /// [`CopyBuilder::on_collision`]
+    Collision,
+    /// The rename closure returned `None` for it, see [`CopyBuilder::with_rename`]
+    Renamed,
+    /// It is a symlink to an entry that is filtered out, see
+    /// [`CopyBuilder::prune_symlinks_to_excluded`]
+    TargetExcluded,
+    /// The overwrite callback declined replacing the destination, see
+    /// [`CopyBuilder::with_overwrite_callback`]
+    Declined,
+    /// It is unchanged from the overlay base, see [`CopyBuilder::overlay_against`]
+    InBase,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+/// The type of an entry in the source, see [`CopyBuilder::with_allowed_types`]
+pub enum FileTypeKind {
+    /// A regular file
+    File,
+    /// A symlink
+    Symlink,
+    /// A directory
+    Dir,
+    /// A named pipe
+    Fifo,
+    /// A Unix domain socket
+    Socket,
+    /// A character device node
+    CharDevice,
+    /// A block device node
+    BlockDevice,
+}
+
+impl FileTypeKind {
+    /// The kind of `file_type`, or `None` if it is none of the known kinds
+    pub fn of(file_type: std::fs::FileType) -> Option<FileTypeKind> {
+        #[cfg(unix)]
+        use std::os::unix::fs::FileTypeExt;
+        if file_type.is_file() {
+            return Some(FileTypeKind::File);
+        } else if file_type.is_symlink() {
+            return Some(FileTypeKind::Symlink);
+        } else if file_type.is_dir() {
+            return Some(FileTypeKind::Dir);
+        }
+        #[cfg(unix)]
+        if file_type.is_fifo() {
+            return Some(FileTypeKind::Fifo);
+        } else if file_type.is_socket() {
+            return Some(FileTypeKind::Socket);
+        } else if file_type.is_char_device() {
+            return Some(FileTypeKind::CharDevice);
+        } else if file_type.is_block_device() {
+            return Some(FileTypeKind::BlockDevice);
+        }
+        None
+    }
+}
+
+impl std::fmt::Display for FileTypeKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            FileTypeKind::File => "file",
+            FileTypeKind::Symlink => "symlink",
+            FileTypeKind::Dir => "dir",
+            FileTypeKind::Fifo => "fifo",
+            FileTypeKind::Socket => "socket",
+            FileTypeKind::CharDevice => "char-device",
+            FileTypeKind::BlockDevice => "block-device",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+/// How to handle files with the same name when flattening or renaming, see
+/// [`CopyBuilder::flatten`] and [`CopyBuilder::with_rename`]
+pub enum Collision {
+    /// Fail with [`CopyError::Collision`]
+    #[default]
+    Error,
+    /// Only copy the first file with a name
+    Skip,
+    /// Copy later files with a name under a numbered one instead, e.g. `libfoo-1.so`
+    Rename,
+    /// Keep the file with the latest modification time, replacing an older one copied earlier
+    KeepNewest,
+}
+
+/// What to do with a single entry of the source tree
+enum Decision {
+    /// Copy a regular file of `len` bytes, or the file a symlink resolves to if `resolved` is set,
+    /// compressing it if `compression` is set
+    CopyFile {
+        len: u64,
+        resolved: Option<PathBuf>,
+        compression: Option<Compression>,
+    },
+    /// Hardlink to `target`, an earlier copy of the same file of `len` bytes
+    Hardlink { target: PathBuf, len: u64 },
+    /// Recreate a symlink
+    Symlink,
+    /// Create a directory
+    CreateDir,
+    /// Leave the destination alone because it is newer than the source
+    ProtectedNewer,
+    /// Recreate a special file
+    Special,
+    /// Skip an entry of a type that cannot be copied
+    Unsupported,
+    /// Skip an entry of a type that is not allowed
+    Disallowed,
+    /// Leave the entry alone, for a reason worth reporting if set
+    Skip(Option<SkipReason>),
+}
+
+impl Decision {
+    /// Why the entry is not copied, if it is skipped for a reason worth reporting
+    fn skip_reason(&self) -> Option<SkipReason> {
+        match self {
+            Decision::ProtectedNewer => Some(SkipReason::ProtectedNewer),
+            Decision::Unsupported => Some(SkipReason::UnsupportedType),
+            Decision::Disallowed => Some(SkipReason::DisallowedType),
+            Decision::Skip(reason) => *reason,
+            _ => None,
+        }
+    }
+
+    /// What carrying out the decision would do, for a [dry run](CopyBuilder::dry_run)
+    fn dry_run(self) -> CopyDecision {
+        match self {
+            Decision::CopyFile { len, .. } => CopyDecision::Copied(len),
+            Decision::Hardlink { len, .. } => CopyDecision::Hardlinked(len),
+            Decision::Symlink => CopyDecision::Symlinked,
+            Decision::CreateDir => CopyDecision::CreatedDir,
+            Decision::Special => CopyDecision::Recreated,
+            decision => CopyDecision::Skipped(decision.skip_reason()),
+        }
+    }
 }
 
 /// Determine if the modification date of file_a is newer than that of file_b
@@ -40,6 +658,156 @@ fn is_file_newer(file_a: &Path, file_b: &Path) -> bool {
     }
 }
 
+/// The device and inode of the regular file `entry` if it has more than one link. Always `None`
+/// on platforms without inodes.
+fn shared_inode(entry: &DirEntry) -> Option<(u64, u64)> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        let metadata = entry.metadata().ok()?;
+        if metadata.is_file() && metadata.nlink() > 1 {
+            return Some((metadata.dev(), metadata.ino()));
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = entry;
+    None
+}
+
+/// The ID of the device containing `path`, without following symlinks. Always `None` on
+/// platforms without device IDs.
+fn device(path: &Path) -> Result<Option<u64>, std::io::Error> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        Ok(Some(path.symlink_metadata()?.dev()))
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = path;
+        Ok(None)
+    }
+}
+
+/// The default limit on open files, half the soft `RLIMIT_NOFILE` limit, leaving the rest for
+/// the standard streams and whatever else the process has open
+fn default_max_open_files() -> usize {
+    #[cfg(unix)]
+    {
+        let mut limit = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        // SAFETY: `limit` is a valid rlimit to write the result to
+        if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } == 0 {
+            return usize::try_from(limit.rlim_cur / 2)
+                .unwrap_or(usize::MAX)
+                .max(2);
+        }
+    }
+    256
+}
+
+/// Drop the cached pages of the file at `path`, first writing them to disk if `flush` is set, as
+/// dirty pages cannot be dropped. Does nothing on platforms other than Linux.
+fn evict_from_page_cache(path: &Path, flush: bool) -> Result<(), std::io::Error> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::fd::AsRawFd;
+        let file = std::fs::File::open(path)?;
+        if flush {
+            file.sync_data()?;
+        }
+        // SAFETY: the file descriptor is valid for the lifetime of `file`
+        let ret = unsafe { libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED) };
+        if ret != 0 {
+            return Err(Error::from_raw_os_error(ret));
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = (path, flush);
+    Ok(())
+}
+
+/// Turn an error moving or linking `src` to `dst` into [`CopyError::CrossDevice`] if they are on
+/// different filesystems
+fn cross_device(e: Error, src: &Path, dst: &Path) -> CopyError {
+    match e.kind() {
+        ErrorKind::CrossesDevices => CopyError::CrossDevice {
+            src: src.to_path_buf(),
+            dst: dst.to_path_buf(),
+        },
+        _ => e.into(),
+    }
+}
+
+/// Determine if `e`, returned by [`CopyBackend::reflink_file`], means cloning is not supported
+/// between the two files rather than that something went wrong
+fn reflink_unsupported(e: &Error) -> bool {
+    if matches!(
+        e.kind(),
+        ErrorKind::Unsupported | ErrorKind::CrossesDevices | ErrorKind::InvalidInput
+    ) {
+        return true;
+    }
+    #[cfg(unix)]
+    if matches!(e.raw_os_error(), Some(libc::ENOTTY | libc::ENOSYS)) {
+        return true;
+    }
+    false
+}
+
+/// Flush the file or directory at `path` to disk
+fn sync_path(path: &Path) -> Result<(), std::io::Error> {
+    std::fs::File::open(path)?.sync_all()
+}
+
+/// Make an existing, read-only regular file writable by its owner
+fn make_writable(path: &Path) -> Result<(), std::io::Error> {
+    let metadata = match path.symlink_metadata() {
+        Ok(metadata) if metadata.is_file() && metadata.permissions().readonly() => metadata,
+        _ => return Ok(()),
+    };
+    debug!("CHMOD +w {}", path.display());
+    let mut permissions = metadata.permissions();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        permissions.set_mode(permissions.mode() | 0o200);
+    }
+    #[cfg(not(unix))]
+    #[allow(clippy::permissions_set_readonly_false)]
+    permissions.set_readonly(false);
+    std::fs::set_permissions(path, permissions)
+}
+
+/// Add the permission bits of `mode` to the file or directory at `path`
+fn add_mode(path: &Path, mode: u32) -> Result<(), std::io::Error> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mut permissions = path.metadata()?.permissions();
+        if permissions.mode() & mode != mode {
+            debug!("CHMOD +{:o} {}", mode, path.display());
+            permissions.set_mode(permissions.mode() | mode);
+            std::fs::set_permissions(path, permissions)?;
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = (path, mode);
+    Ok(())
+}
+
+/// Rewrite the relative path `rel` through the first matching `(from, to)` rule of `remaps`
+pub(crate) fn remap_path(remaps: &[(PathBuf, PathBuf)], rel: &Path) -> PathBuf {
+    for (from, to) in remaps {
+        if let Ok(rest) = rel.strip_prefix(from) {
+            return to.join(rest);
+        }
+    }
+    rel.to_path_buf()
+}
+
 /// Determine if file_a and file_b's size differs.
 fn is_filesize_different(file_a: &Path, file_b: &Path) -> bool {
     match (file_a.symlink_metadata(), file_b.symlink_metadata()) {
@@ -48,6 +816,17 @@ fn is_filesize_different(file_a: &Path, file_b: &Path) -> bool {
     }
 }
 
+/// Determine if the contents of file_a and file_b differ. Anything but two regular files, or
+/// files that cannot be read, are considered different.
+fn is_content_different(file_a: &Path, file_b: &Path) -> bool {
+    match (file_a.symlink_metadata(), file_b.symlink_metadata()) {
+        (Ok(meta_a), Ok(meta_b)) if meta_a.is_file() && meta_b.is_file() => {
+            compare::contents_differ(file_a, file_b).unwrap_or(true)
+        }
+        _ => true,
+    }
+}
+
 impl CopyBuilder {
     /// Construct a new CopyBuilder with `source` and `dest`.
     pub fn new<P: AsRef<Path>, Q: AsRef<Path>>(source: P, dest: Q) -> CopyBuilder {
@@ -57,10 +836,65 @@ impl CopyBuilder {
             overwrite_all: false,
             overwrite_if_newer: false,
             overwrite_if_size_differs: false,
-            exclude_filters: vec![],
-            include_filters: vec![],
-            exclude_paths: vec![],
-            include_paths: vec![],
+            overwrite_if_content_differs: false,
+            protect_newer_destination: false,
+            force_overwrite_readonly: false,
+            filters: Filters::default(),
+            auto_ignore_file: None,
+            error_on_empty: false,
+            dangling_symlinks: DanglingMode::Keep,
+            prune_symlinks_to_excluded: false,
+            normalize_relative_symlinks: false,
+            special_files: SpecialFiles::Skip,
+            sorted: false,
+            same_filesystem: false,
+            max_total_bytes: None,
+            max_files: None,
+            timeout: None,
+            fixed_mtime: None,
+            preserve_timestamps: false,
+            modified_since: None,
+            max_file_size: None,
+            min_file_size: None,
+            bypass_page_cache: false,
+            sync: SyncMode::None,
+            tree_hash: None,
+            continue_on_error: false,
+            threads: 1,
+            dry_run: false,
+            preserve_inode_flags: false,
+            preserve_special_bits: false,
+            preserve_permissions: false,
+            preserve_hardlinks: false,
+            follow_symlinks: false,
+            max_depth: None,
+            reflink: ReflinkMode::Never,
+            buffer_size: None,
+            min_mode: None,
+            allowed_types: None,
+            copy_root_metadata: false,
+            resume: false,
+            on_skip: None,
+            on_progress: None,
+            on_overwrite: None,
+            preserve_ownership: false,
+            uid_map: None,
+            gid_map: None,
+            delete_extraneous: false,
+            mirror_protect: vec![],
+            overlay_base: None,
+            remaps: vec![],
+            rename: None,
+            compress: vec![],
+            roots: vec![],
+            flatten: false,
+            on_collision: Collision::Error,
+            origin_manifest: None,
+            source_manifest: None,
+            atomic: false,
+            temp_dir: None,
+            open_files: Arc::new(Semaphore::new(default_max_open_files())),
+            backend: Arc::new(StdBackend),
         }
     }
 
@@ -88,194 +922,3578 @@ impl CopyBuilder {
         }
     }
 
-    /// Do not copy files that contain this string
-    pub fn with_exclude_filter(self, f: &str) -> CopyBuilder {
-        let mut filters = self.exclude_filters.clone();
-        filters.push(f.to_owned());
+    /// Overwrite if the contents of source and dest differ (off by default).
+    ///
+    /// Files of different sizes are copied right away, and others are compared byte by byte,
+    /// stopping at the first difference. With the `mmap` feature, both files are memory mapped
+    /// for the comparison, which is faster for large files.
+    ///
+    /// Like the other `overwrite_if_*` options, an existing file is overwritten if any of those
+    /// enabled finds it outdated.
+    pub fn overwrite_if_content_differs(self, overwrite_if_content_differs: bool) -> CopyBuilder {
         CopyBuilder {
-            exclude_filters: filters,
+            overwrite_if_content_differs,
             ..self
         }
     }
 
-    /// Only copy files that contain this string.
-    pub fn with_include_filter(self, f: &str) -> CopyBuilder {
-        let mut filters = self.include_filters.clone();
-        filters.push(f.to_owned());
+    /// Never overwrite a target file that is newer than the source, regardless of the other
+    /// overwrite options (off by default)
+    pub fn protect_newer_destination(self, protect_newer_destination: bool) -> CopyBuilder {
         CopyBuilder {
-            include_filters: filters,
+            protect_newer_destination,
             ..self
         }
     }
 
-    /// Do not copy these paths
-    pub fn with_exclude_path(self, f: &str) -> CopyBuilder {
-        let mut paths = self.exclude_paths.clone();
-        paths.push(f.to_owned());
+    /// Make read-only target files writable by their owner before overwriting them, instead of
+    /// failing with a permission error (off by default).
+    ///
+    /// This weakens the protection read-only files offer, but is needed to copy again into a
+    /// target whose files kept restrictive modes from an earlier copy.
+    pub fn force_overwrite_readonly(self, force_overwrite_readonly: bool) -> CopyBuilder {
         CopyBuilder {
-            exclude_paths: paths,
+            force_overwrite_readonly,
             ..self
         }
     }
 
+    /// Do not copy files that contain this string
+    pub fn with_exclude_filter(self, f: &str) -> CopyBuilder {
+        let mut filters = self.filters.clone();
+        filters.push(false, Matcher::Substring(f.to_owned()));
+        CopyBuilder { filters, ..self }
+    }
+
+    /// Only copy files that contain this string.
+    pub fn with_include_filter(self, f: &str) -> CopyBuilder {
+        let mut filters = self.filters.clone();
+        filters.push(true, Matcher::Substring(f.to_owned()));
+        CopyBuilder { filters, ..self }
+    }
+
+    /// Do not copy files whose path matches this regex anywhere, failing if it does not compile
+    pub fn with_exclude_regex(self, re: &str) -> Result<CopyBuilder, regex::Error> {
+        let mut filters = self.filters.clone();
+        filters.push(false, Matcher::regex(re)?);
+        Ok(CopyBuilder { filters, ..self })
+    }
+
+    /// Only copy files whose path matches this regex anywhere, failing if it does not compile
+    pub fn with_include_regex(self, re: &str) -> Result<CopyBuilder, regex::Error> {
+        let mut filters = self.filters.clone();
+        filters.push(true, Matcher::regex(re)?);
+        Ok(CopyBuilder { filters, ..self })
+    }
+
+    /// Do not copy entries whose path relative to the source matches this glob, like `**/*.a`,
+    /// or anything below them, failing if it does not compile. Like every other kind of
+    /// exclude, a match excludes the entry even if it also contains an include filter, unless
+    /// the [`Precedence`] says otherwise.
+    pub fn with_exclude_glob(self, glob: &str) -> Result<CopyBuilder, globset::Error> {
+        let mut filters = self.filters.clone();
+        filters.push(false, Matcher::glob(glob)?);
+        Ok(CopyBuilder { filters, ..self })
+    }
+
+    /// Only copy entries whose path relative to the source matches this glob, failing if it
+    /// does not compile. Directories are still walked for matches below them.
+    pub fn with_include_glob(self, glob: &str) -> Result<CopyBuilder, globset::Error> {
+        let mut filters = self.filters.clone();
+        filters.push(true, Matcher::glob(glob)?);
+        Ok(CopyBuilder { filters, ..self })
+    }
+
+    /// Do not copy these paths
+    pub fn with_exclude_path(self, f: &str) -> CopyBuilder {
+        let mut filters = self.filters.clone();
+        filters.push(false, Matcher::Path(PathBuf::from(f)));
+        CopyBuilder { filters, ..self }
+    }
+
     /// Only not copy these paths
     pub fn with_include_path(self, f: &str) -> CopyBuilder {
-        let mut paths = self.include_paths.clone();
-        paths.push(f.to_owned());
+        let mut filters = self.filters.clone();
+        filters.push(true, Matcher::Path(PathBuf::from(f)));
+        CopyBuilder { filters, ..self }
+    }
+
+    /// Do not copy entries with a path component below the source named exactly `name`, or
+    /// anything below them. Unlike [`CopyBuilder::with_exclude_filter`], `bin` does not exclude
+    /// `mybinaries`.
+    pub fn with_exclude_component(self, name: &str) -> CopyBuilder {
+        let mut filters = self.filters.clone();
+        filters.push(false, Matcher::Component(name.to_owned()));
+        CopyBuilder { filters, ..self }
+    }
+
+    /// Do not copy version control directories and common build artifacts anywhere in the
+    /// source, i.e. any entry named like one of [`VCS_EXCLUDES`], and everything below it.
+    ///
+    /// These excludes are added before all others, so later includes can override them when
+    /// the last match wins.
+    pub fn with_vcs_excludes(self) -> CopyBuilder {
+        let mut filters = self.filters.clone();
+        for name in VCS_EXCLUDES.iter().rev() {
+            filters.prepend(false, Matcher::Component(name.to_string()));
+        }
+        CopyBuilder { filters, ..self }
+    }
+
+    /// Match include and exclude filters, regexes and globs regardless of case (off by default),
+    /// e.g. for a source from a case-insensitive filesystem. This only affects matching, the
+    /// names of copied files are kept as they are. Include and exclude paths and excluded path
+    /// components are still matched exactly.
+    pub fn case_insensitive_filters(self, case_insensitive: bool) -> CopyBuilder {
+        let mut filters = self.filters.clone();
+        filters.set_case_insensitive(case_insensitive);
+        CopyBuilder { filters, ..self }
+    }
+
+    /// How to resolve entries matched by both include and exclude paths or filters (exclude wins
+    /// by default). Rules are considered in the order they were added.
+    pub fn filter_precedence(self, precedence: Precedence) -> CopyBuilder {
+        let mut filters = self.filters.clone();
+        filters.set_precedence(precedence);
+        CopyBuilder { filters, ..self }
+    }
+
+    /// Apply the gitignore style rules in the file with this name at the source root, if it
+    /// exists (off by default). The ignore file itself is never copied.
+    pub fn auto_ignore_file(self, auto_ignore_file: Option<String>) -> CopyBuilder {
         CopyBuilder {
-            include_paths: paths,
+            auto_ignore_file,
             ..self
         }
     }
 
-    /// Execute the copy operation
-    pub fn run(&self) -> Result<(), std::io::Error> {
-        if !self.destination.is_dir() {
-            debug!("MKDIR {:?}", &self.destination);
-            std::fs::create_dir_all(&self.destination)?;
+    /// Fail with [`CopyError::SourceEmpty`] if the source directory is empty (off by default)
+    pub fn error_on_empty(self, error_on_empty: bool) -> CopyBuilder {
+        CopyBuilder {
+            error_on_empty,
+            ..self
         }
-        let abs_source = self.source.canonicalize()?;
-        let abs_dest = self.destination.canonicalize()?;
-        let exclude_paths: Vec<PathBuf> = self
-            .exclude_paths
-            .clone()
-            .into_iter()
-            .map(|s| PathBuf::from(s))
-            .collect();
-        let include_paths: Vec<PathBuf> = self
-            .include_paths
-            .clone()
-            .into_iter()
-            .map(|s| PathBuf::from(s))
-            .collect();
-        debug!(
-            "Building copy operation: SRC {} DST {}",
-            abs_source.display(),
-            abs_dest.display()
-        );
+    }
 
-        'files: for entry in WalkDir::new(&abs_source)
-            .into_iter()
-            .filter_entry(|e| {
-                let mut included = false;
-                if include_paths.len() == 0 || e.path() == abs_source {
-                    included = true;
-                } else {
-                    'include: for path in include_paths.iter() {
-                        if e.path().starts_with(path) || path.starts_with(e.path()) {
-                            included = true;
-                            break 'include;
-                        }
-                    }
-                }
-                let mut excluded = false;
-                'exclude: for path in exclude_paths.iter() {
-                    if e.path().starts_with(path) {
-                        excluded = true;
-                        break 'exclude;
-                    }
-                }
-                e.path() != abs_dest && !excluded && included
-            })
-            .filter_map(|e| e.ok())
-        {
-            let rel_dest = entry.path().strip_prefix(&abs_source).map_err(|e| {
-                Error::new(ErrorKind::Other, format!("Could not strip prefix: {:?}", e))
-            })?;
-            let dest_entry = abs_dest.join(rel_dest);
+    /// How to handle symlinks whose target does not exist inside the source
+    /// (`DanglingMode::Keep` by default)
+    pub fn dangling_symlinks(self, dangling_symlinks: DanglingMode) -> CopyBuilder {
+        CopyBuilder {
+            dangling_symlinks,
+            ..self
+        }
+    }
 
-            if entry.path().symlink_metadata().is_ok() && !entry.file_type().is_dir() {
-                // the source exists, but isn't a directory
+    /// Skip symlinks whose target, resolved inside the source, is filtered out by the include and
+    /// exclude paths and filters (off by default). Otherwise excluding a directory such as
+    /// `/usr/share/doc` leaves every symlink into it dangling in the destination.
+    pub fn prune_symlinks_to_excluded(self, prune_symlinks_to_excluded: bool) -> CopyBuilder {
+        CopyBuilder {
+            prune_symlinks_to_excluded,
+            ..self
+        }
+    }
 
-                // Early out if target is present and overwrite is off
-                if !self.overwrite_all
-                    && dest_entry.symlink_metadata().is_ok()
-                    && !self.overwrite_if_newer
-                    && !self.overwrite_if_size_differs
-                {
-                    continue;
-                }
+    /// Rewrite the target of every relative symlink so it points to the copy of the same entry
+    /// after [remapping](CopyBuilder::with_remap) or [renaming](CopyBuilder::with_rename)
+    /// (off by default). Targets climbing out of the source, or to an entry renamed away, are
+    /// kept as they are.
+    pub fn normalize_relative_symlinks(self, normalize_relative_symlinks: bool) -> CopyBuilder {
+        CopyBuilder {
+            normalize_relative_symlinks,
+            ..self
+        }
+    }
 
-                for f in &self.exclude_filters {
-                    debug!("EXCL {} for {:?}", f, entry);
+    /// How to handle special files, i.e. sockets, FIFOs and device nodes
+    /// (`SpecialFiles::Skip` by default). Overridden by [`CopyBuilder::with_allowed_types`].
+    pub fn special_files(self, special_files: SpecialFiles) -> CopyBuilder {
+        CopyBuilder {
+            special_files,
+            ..self
+        }
+    }
 
-                    if entry.path().to_string_lossy().contains(f) {
-                        continue 'files;
-                    }
-                }
+    /// Visit entries in file name order instead of filesystem order (off by default).
+    ///
+    /// This makes the order of operations, and therefore logs, deterministic across runs and
+    /// machines at a small performance cost. Turn it on for reproducible builds.
+    pub fn sorted(self, sorted: bool) -> CopyBuilder {
+        CopyBuilder { sorted, ..self }
+    }
 
-                if !self.include_filters.is_empty()
-                    && !self
-                        .include_filters
-                        .iter()
-                        .any(|f| entry.path().to_string_lossy().contains(f))
-                {
-                    continue 'files;
-                }
+    /// Skip every entry on a different filesystem than the source root, like `find -xdev` or
+    /// `rsync -x` (off by default). Mount points themselves are not copied either.
+    pub fn same_filesystem(self, same_filesystem: bool) -> CopyBuilder {
+        CopyBuilder {
+            same_filesystem,
+            ..self
+        }
+    }
 
-                // File is not present: copy it in any case
-                let dest_exists = dest_entry.symlink_metadata().is_ok();
+    /// Fail with [`CopyError::LimitExceeded`] instead of copying more than `max` bytes of file
+    /// contents. The limit is checked before each file, so the copy stops early.
+    pub fn with_max_total_bytes(self, max: u64) -> CopyBuilder {
+        CopyBuilder {
+            max_total_bytes: Some(max),
+            ..self
+        }
+    }
 
-                if !dest_exists {
-                    debug!(
-                        "Dest not present: CP {} DST {}",
-                        entry.path().display(),
-                        dest_entry.display()
-                    );
-                }
+    /// Fail with [`CopyError::LimitExceeded`] instead of copying more than `max` regular files
+    pub fn with_max_files(self, max: usize) -> CopyBuilder {
+        CopyBuilder {
+            max_files: Some(max as u64),
+            ..self
+        }
+    }
 
-                // File newer?
-                if dest_exists && self.overwrite_if_newer {
-                    if is_file_newer(entry.path(), &dest_entry) {
-                        debug!(
-                            "Source newer: CP {} DST {}",
-                            entry.path().display(),
-                            dest_entry.display()
-                        );
-                    } else {
-                        continue;
-                    }
-                }
+    /// Fail with [`CopyError::TimedOut`] once the copy has taken longer than `timeout` (off by
+    /// default), e.g. to catch a misconfiguration pulling in a huge tree. The time is checked
+    /// before each entry, so copying a single large file may overrun the timeout, but no file is
+    /// left half copied.
+    pub fn with_timeout(self, timeout: Duration) -> CopyBuilder {
+        CopyBuilder {
+            timeout: Some(timeout),
+            ..self
+        }
+    }
 
-                // Different size?
-                if dest_exists && self.overwrite_if_size_differs {
-                    if is_filesize_different(entry.path(), &dest_entry) {
-                        debug!(
-                            "Source differs: CP {} DST {}",
-                            entry.path().display(),
-                            dest_entry.display()
-                        );
-                    } else {
-                        continue;
-                    }
-                }
+    /// Set the access and modification times of every copied file, symlink and directory to
+    /// `mtime`, e.g. `SOURCE_DATE_EPOCH`, for bit-reproducible output (off by default).
+    ///
+    /// This normalizes timestamps rather than preserving them from the source.
+    pub fn with_fixed_mtime(self, mtime: SystemTime) -> CopyBuilder {
+        CopyBuilder {
+            fixed_mtime: Some(mtime),
+            ..self
+        }
+    }
 
-                if entry.file_type().is_file() {
-                    // The regular copy operation
-                    debug!("CP {} DST {}", entry.path().display(), dest_entry.display());
-                    copy(entry.path(), dest_entry)?;
-                } else if entry.file_type().is_symlink() {
-                    debug!(
-                        "CP LNK {} DST {}",
-                        entry.path().display(),
-                        dest_entry.display()
-                    );
-                    let target = read_link(entry.path())?;
-                    #[cfg(unix)]
-                    std::os::unix::fs::symlink(target, dest_entry)?
-                } else {
-                    eprintln!(
-                        "File {} has unhalded type {:?}, skipping",
-                        entry.path().display(),
-                        entry.file_type()
-                    );
-                }
-            } else if entry.path().is_dir() && !dest_entry.is_dir() {
-                debug!("MKDIR {}", entry.path().display());
-                std::fs::create_dir_all(dest_entry)?;
-            }
+    /// Give every copied file, symlink and directory the access and modification times of its
+    /// source (off by default), so build systems comparing timestamps do not consider the
+    /// sysroot newer than what was built against it. Directories get theirs once everything is
+    /// copied, as writing their children updates them. Cannot be combined with
+    /// [`CopyBuilder::with_fixed_mtime`].
+    pub fn preserve_timestamps(self, preserve_timestamps: bool) -> CopyBuilder {
+        CopyBuilder {
+            preserve_timestamps,
+            ..self
         }
+    }
 
-        Ok(())
+    /// Only copy files and symlinks whose modification time in the source is at or after
+    /// `since`, e.g. the time of the last build (off by default). Directories are still created.
+    ///
+    /// Unlike the overwrite options, this only looks at the source, so it also works without a
+    /// previous destination to compare against.
+    pub fn with_modified_since(self, since: SystemTime) -> CopyBuilder {
+        CopyBuilder {
+            modified_since: Some(since),
+            ..self
+        }
+    }
+
+    /// Skip regular files larger than `bytes` bytes in the source (unlimited by default), e.g.
+    /// firmware blobs. Directories and symlinks are never skipped for their size.
+    pub fn max_file_size(self, bytes: u64) -> CopyBuilder {
+        CopyBuilder {
+            max_file_size: Some(bytes),
+            ..self
+        }
+    }
+
+    /// Skip regular files smaller than `bytes` bytes in the source (none by default). Directories
+    /// and symlinks are never skipped for their size.
+    pub fn min_file_size(self, bytes: u64) -> CopyBuilder {
+        CopyBuilder {
+            min_file_size: Some(bytes),
+            ..self
+        }
+    }
+
+    /// Evict the source and destination of each copied file from the page cache once it is
+    /// copied, so copying a large tree does not push everything else out of it (off by default).
+    ///
+    /// Each destination file is flushed to disk before it is evicted, which makes the copy
+    /// slower, and later reads of the copied files have to go to disk. Only supported on Linux,
+    /// elsewhere this does nothing.
+    pub fn bypass_page_cache(self, bypass_page_cache: bool) -> CopyBuilder {
+        CopyBuilder {
+            bypass_page_cache,
+            ..self
+        }
+    }
+
+    /// Flush copied data to disk with fsync before returning (`SyncMode::None` by default), so a
+    /// power loss right after the copy cannot leave truncated or missing files behind.
+    ///
+    /// `SyncMode::Files` syncs each copied file once it is written and the destination root
+    /// at the end. `SyncMode::Full` additionally syncs every directory entries were written to,
+    /// which makes new names durable. Both slow down the copy considerably.
+    pub fn sync(self, sync: SyncMode) -> CopyBuilder {
+        CopyBuilder { sync, ..self }
+    }
+
+    /// Hash the whole destination once copied and return the hash in [`CopyStats::tree_hash`]
+    /// (off by default), e.g. to key a build cache on it. The hash covers the path, type and
+    /// permission bits of every entry, the contents of files and the targets of symlinks, but not
+    /// timestamps or ownership. Identical trees hash the same regardless of the order their
+    /// entries are listed in.
+    ///
+    /// This reads back everything in the destination, including entries not copied by this run.
+    pub fn with_tree_hash(self, algo: HashAlgo) -> CopyBuilder {
+        CopyBuilder {
+            tree_hash: Some(algo),
+            ..self
+        }
+    }
+
+    /// Carry on with the remaining entries when copying one fails (off by default), e.g. to find
+    /// every unreadable file in one pass. [`CopyBuilder::run_with_failures`] returns the failures
+    /// along with the statistics, while `run()` still fails with the first one, once everything
+    /// else is copied.
+    ///
+    /// Only failures writing single entries are collected. Errors deciding what to copy, such as
+    /// [collisions](CopyError::Collision), and limits still stop the copy right away.
+    pub fn continue_on_error(self, continue_on_error: bool) -> CopyBuilder {
+        CopyBuilder {
+            continue_on_error,
+            ..self
+        }
+    }
+
+    /// Make `run()` and its variants, [`CopyBuilder::copy_one`] and
+    /// [`CopyBuilder::apply_changes`] only decide what to do, without writing anything to the
+    /// destination (off by default). They return statistics about what would be done, with
+    /// everything after the walk, such as deleting extraneous entries and hashing the tree,
+    /// left out. See [`CopyBuilder::run_dry`] for the operations themselves.
+    pub fn dry_run(self, dry_run: bool) -> CopyBuilder {
+        CopyBuilder { dry_run, ..self }
+    }
+
+    /// Copy files, symlinks and special files on `threads` threads (1 by default, copying
+    /// everything on the calling thread). The source is still walked on the calling thread, which
+    /// creates each directory before handing out anything below it. Files are still limited by
+    /// [`CopyBuilder::with_max_open_files`].
+    ///
+    /// Entries finish in any order, so the [origin manifest](CopyBuilder::with_origin_manifest)
+    /// is not in walk order. The first failure still stops the copy, once the copies already
+    /// running are done.
+    pub fn threads(self, threads: usize) -> CopyBuilder {
+        CopyBuilder {
+            threads: threads.max(1),
+            ..self
+        }
+    }
+
+    /// Apply the immutable and append-only inode flags (`chattr +i`, `chattr +a`) of copied
+    /// files to their copies (off by default). Only supported on Linux, elsewhere this does
+    /// nothing.
+    ///
+    /// Setting these flags requires `CAP_LINUX_IMMUTABLE`. Without it, a warning is logged and
+    /// the copy is left without them. Files copied with these flags cannot be overwritten by a
+    /// later copy until the flags are removed.
+    pub fn preserve_inode_flags(self, preserve_inode_flags: bool) -> CopyBuilder {
+        CopyBuilder {
+            preserve_inode_flags,
+            ..self
+        }
+    }
+
+    /// Give every copied file and created directory exactly the setuid, setgid and sticky bits of
+    /// its source (off by default).
+    ///
+    /// The bits are set once everything else is written, so they survive backends and
+    /// compression that create files with default permissions, and the kernel clearing setuid
+    /// and setgid when [preserving ownership](CopyBuilder::preserve_ownership). Without this,
+    /// directories never get these bits, and files only keep them if the backend copies them.
+    pub fn preserve_special_bits(self, preserve_special_bits: bool) -> CopyBuilder {
+        CopyBuilder {
+            preserve_special_bits,
+            ..self
+        }
+    }
+
+    /// Give every copied file and created directory exactly the permissions of its source,
+    /// including the setuid, setgid and sticky bits (off by default). Otherwise directories are
+    /// created with the umask, and files keep whatever the backend gives them.
+    ///
+    /// Directories get their permissions once everything is copied, deepest first, so read-only
+    /// directories can still be written to. The destination itself is left to
+    /// [`CopyBuilder::copy_root_metadata`]. [`CopyBuilder::with_min_mode`] still applies on top.
+    pub fn preserve_permissions(self, preserve_permissions: bool) -> CopyBuilder {
+        CopyBuilder {
+            preserve_permissions,
+            ..self
+        }
+    }
+
+    /// Keep source files that are hardlinks to each other linked in the destination (off by
+    /// default), like `cp -a` and `rsync -H`. The first of them is copied, and the others are
+    /// hardlinked to that copy instead of being copied again. The bytes saved are counted in
+    /// [`CopyStats::reclaimed_bytes`]. Links that are filtered out are not copied, so the first
+    /// one copied is used, and if its copy failed the others are copied on their own.
+    ///
+    /// Only files copied as they are take part, not compressed files or files symlinks resolve
+    /// to when flattening. [`CopyBuilder::copy_one`] copies every file on its own. Only supported
+    /// on Unix, elsewhere this does nothing.
+    pub fn preserve_hardlinks(self, preserve_hardlinks: bool) -> CopyBuilder {
+        CopyBuilder {
+            preserve_hardlinks,
+            ..self
+        }
+    }
+
+    /// Copy the files and directories symlinks point to in place of the symlinks (off by
+    /// default), like `cp -L`. Targets are resolved inside the source as if it were `/`, so
+    /// `/usr/lib/libc.so` is the one in the source, never the host's. Symlinks that do not
+    /// resolve are skipped as [`SkipReason::Dangling`], and a symlink to one of its own ancestors
+    /// fails the copy. Does not combine with [`CopyBuilder::preserve_hardlinks`].
+    pub fn follow_symlinks(self, follow_symlinks: bool) -> CopyBuilder {
+        CopyBuilder {
+            follow_symlinks,
+            ..self
+        }
+    }
+
+    /// Only copy entries at most `max_depth` levels below the source (unlimited by default).
+    /// At 0 only the destination itself is created, at 1 only the entries directly in the
+    /// source are copied, and directories at the last level are created empty.
+    pub fn max_depth(self, max_depth: usize) -> CopyBuilder {
+        CopyBuilder {
+            max_depth: Some(max_depth),
+            ..self
+        }
+    }
+
+    /// Whether to clone regular files copy-on-write instead of copying their contents
+    /// (`ReflinkMode::Never` by default), which is nearly instant and shares their disk space
+    /// until either side is modified. Needs the source and destination on the same Btrfs, XFS
+    /// or APFS filesystem, or another one the backend can clone on, see
+    /// [`CopyBackend::reflink_file`]. Compressed files are always copied. Does not combine with
+    /// [`CopyBuilder::preserve_hardlinks`].
+    pub fn reflink(self, reflink: ReflinkMode) -> CopyBuilder {
+        CopyBuilder { reflink, ..self }
+    }
+
+    /// Copy files by reading and writing `buffer_size` bytes at a time (at least 1), e.g. to
+    /// tune copies to a slow network mount. By default files are copied with
+    /// [`std::fs::copy`], which lets the kernel copy them where it can. Progress is reported
+    /// once per buffer, see [`CopyBackend::copy_file_buffered`].
+    pub fn buffer_size(self, buffer_size: usize) -> CopyBuilder {
+        CopyBuilder {
+            buffer_size: Some(buffer_size.max(1)),
+            ..self
+        }
+    }
+
+    /// Only copy entries of the given types (`File`, `Symlink` and `Dir` by default), skipping and
+    /// counting the rest in [`CopyStats::disallowed`]. Allowed special files are recreated like
+    /// with `SpecialFiles::Recreate`, regardless of [`CopyBuilder::special_files`]. Disallowing
+    /// `Dir` skips every directory below the source along with its contents.
+    pub fn with_allowed_types(self, types: &[FileTypeKind]) -> CopyBuilder {
+        CopyBuilder {
+            allowed_types: Some(types.to_vec()),
+            ..self
+        }
+    }
+
+    /// Give the destination directory itself the permissions of the source directory once the
+    /// copy is done (off by default). Otherwise it keeps the permissions it was created with.
+    pub fn copy_root_metadata(self, copy_root_metadata: bool) -> CopyBuilder {
+        CopyBuilder {
+            copy_root_metadata,
+            ..self
+        }
+    }
+
+    /// Skip destination files with the same size and modification time as their source, to
+    /// quickly resume an interrupted copy (off by default), as well as symlinks already pointing
+    /// to the same target. Copied files are given the modification time of their source (or the
+    /// fixed one, see [`CopyBuilder::with_fixed_mtime`]) once complete, so a file cut short by
+    /// the interruption is copied again.
+    ///
+    /// This trusts size and modification time without reading the files, so it is not a
+    /// guarantee that the destination matches the source. Any other file is copied, overriding
+    /// the other overwrite options, including files from an earlier copy without this option,
+    /// except for files [protected](CopyBuilder::protect_newer_destination) for being newer
+    /// than their source.
+    pub fn resume(self, resume: bool) -> CopyBuilder {
+        CopyBuilder { resume, ..self }
+    }
+
+    /// Call `on_skip` with every entry of the source that is not copied and why, e.g. to find out
+    /// why a file is missing from the destination. Excluded directories are reported without
+    /// their contents. Entries are reported whenever the source is walked, by
+    /// [`CopyBuilder::plan`] and [`CopyBuilder::estimate`] as well as by a copy.
+    pub fn with_skip_callback<F>(self, on_skip: F) -> CopyBuilder
+    where
+        F: Fn(&Path, SkipReason) + Send + Sync + 'static,
+    {
+        CopyBuilder {
+            on_skip: Some(Callback::new(Arc::new(on_skip))),
+            ..self
+        }
+    }
+
+    /// Call `on_progress` with the source file being copied, the bytes of it copied so far and its
+    /// size as each file is copied, e.g. to display a progress bar. It is called at least once
+    /// for every file, when it is done. The default backend also calls it after every chunk of a
+    /// file, other backends may only call it when done, see
+    /// [`CopyBackend::copy_file_with_progress`]. Files are copied on every thread set with
+    /// [`CopyBuilder::threads`], so it may be called from several at once.
+    pub fn on_progress<F>(self, on_progress: F) -> CopyBuilder
+    where
+        F: Fn(&Path, u64, u64) + Send + Sync + 'static,
+    {
+        CopyBuilder {
+            on_progress: Some(Callback::new(Arc::new(on_progress))),
+            ..self
+        }
+    }
+
+    /// Call `on_overwrite` with the source and the destination of every existing destination file
+    /// about to be replaced, once the overwrite options decided to replace it, e.g. to ask the
+    /// user like `cp -i`. Returning `false` leaves the destination alone and skips the file with
+    /// [`SkipReason::Declined`]. It is only called while copying, not by [`CopyBuilder::plan`],
+    /// [`CopyBuilder::estimate`] or [`CopyBuilder::diff`].
+    pub fn with_overwrite_callback<F>(self, on_overwrite: F) -> CopyBuilder
+    where
+        F: Fn(&Path, &Path) -> bool + Send + Sync + 'static,
+    {
+        CopyBuilder {
+            on_overwrite: Some(Callback::new(Arc::new(on_overwrite))),
+            ..self
+        }
+    }
+
+    /// Give every copied file, symlink, special file and directory the owner and group of its
+    /// source (off by default). Symlinks themselves are changed, not their targets. Changing the
+    /// owner requires `CAP_CHOWN`, without it the copy fails with a permission error naming the
+    /// entry, or collects one per entry with [`CopyBuilder::continue_on_error`]. Only supported
+    /// on Unix, elsewhere this does nothing.
+    pub fn preserve_ownership(self, preserve_ownership: bool) -> CopyBuilder {
+        CopyBuilder {
+            preserve_ownership,
+            ..self
+        }
+    }
+
+    /// Translate the user ID of every source entry through `uid_map` when
+    /// [preserving ownership](CopyBuilder::preserve_ownership), like `tar --owner-map`
+    pub fn with_uid_map<F>(self, uid_map: F) -> CopyBuilder
+    where
+        F: Fn(u32) -> u32 + Send + Sync + 'static,
+    {
+        CopyBuilder {
+            uid_map: Some(Callback::new(Arc::new(uid_map))),
+            ..self
+        }
+    }
+
+    /// Translate the group ID of every source entry through `gid_map` when
+    /// [preserving ownership](CopyBuilder::preserve_ownership), like `tar --group-map`
+    pub fn with_gid_map<F>(self, gid_map: F) -> CopyBuilder
+    where
+        F: Fn(u32) -> u32 + Send + Sync + 'static,
+    {
+        CopyBuilder {
+            gid_map: Some(Callback::new(Arc::new(gid_map))),
+            ..self
+        }
+    }
+
+    /// Shift every user and group ID by `offset` when
+    /// [preserving ownership](CopyBuilder::preserve_ownership), e.g. for a container with a user
+    /// namespace starting at `offset`. Replaces any maps set before.
+    pub fn with_id_offset(self, offset: u32) -> CopyBuilder {
+        self.with_uid_map(move |uid| uid.saturating_add(offset))
+            .with_gid_map(move |gid| gid.saturating_add(offset))
+    }
+
+    /// Add the permission bits of `file_mode` to every copied file and of `dir_mode` to every
+    /// created directory, e.g. `0o644` and `0o755` to make the sysroot readable by everyone
+    /// (off by default). Bits already set are never removed. Only supported on Unix, elsewhere
+    /// this does nothing.
+    pub fn with_min_mode(self, file_mode: u32, dir_mode: u32) -> CopyBuilder {
+        CopyBuilder {
+            min_mode: Some((file_mode, dir_mode)),
+            ..self
+        }
+    }
+
+    /// After copying, delete everything in the target that is not present in the source, like
+    /// `rsync --delete` (off by default). Like rsync, entries matching an exclude path or filter
+    /// are kept along with everything below them, while entries that are only not included are
+    /// deleted. See [`CopyBuilder::with_mirror_protect`] to keep others.
+    pub fn delete_extraneous(self, delete_extraneous: bool) -> CopyBuilder {
+        CopyBuilder {
+            delete_extraneous,
+            ..self
+        }
+    }
+
+    /// Never delete target paths matching this glob or path prefix, relative to the target, when
+    /// deleting extraneous files
+    pub fn with_mirror_protect(self, f: &str) -> CopyBuilder {
+        let mut patterns = self.mirror_protect.clone();
+        patterns.push(f.to_owned());
+        CopyBuilder {
+            mirror_protect: patterns,
+            ..self
+        }
+    }
+
+    /// Produce an overlay layer on top of the directory `base` instead of a full copy (off by
+    /// default), e.g. the upper directory of an overlayfs mount over a base sysroot.
+    ///
+    /// Only entries that are missing from `base` or differ from it are copied. Files are
+    /// compared by size and modification time, or by contents if
+    /// [`CopyBuilder::overwrite_if_content_differs`] is set, and symlinks by target. The
+    /// overwrite options still decide whether a differing file replaces one already in the
+    /// destination. For every entry of `base` missing from the source, an empty `.wh.<name>`
+    /// whiteout marker is created next to where it would be. Entries that are only excluded by
+    /// the filters are not whited out. This does not apply when flattening.
+    pub fn overlay_against<P: AsRef<Path>>(self, base: P) -> CopyBuilder {
+        CopyBuilder {
+            overlay_base: Some(base.as_ref().to_path_buf()),
+            ..self
+        }
+    }
+
+    /// Place everything below the source path `from` below `to` in the target instead, e.g. to
+    /// merge `/usr/lib64` into `/usr/lib`. The first matching rule wins. Absolute symlink targets
+    /// below `from` are rewritten as well.
+    pub fn with_remap(self, from: &str, to: &str) -> CopyBuilder {
+        let mut remaps = self.remaps.clone();
+        remaps.push((
+            PathBuf::from(from.trim_start_matches('/')),
+            PathBuf::from(to.trim_start_matches('/')),
+        ));
+        CopyBuilder { remaps, ..self }
+    }
+
+    /// Copy every entry to the path `rename` returns for its path relative to the source, relative
+    /// to the destination, or skip it if that is `None` (off by default), e.g. to strip version
+    /// suffixes or lowercase names. This replaces the remap rules.
+    ///
+    /// Directories are renamed too, but their contents are not moved along, so `rename` has to
+    /// rename them consistently. Files renamed to the same path are handled according to
+    /// [`CopyBuilder::on_collision`]. Symlink targets are not renamed, so links to renamed files
+    /// have to be fixed up separately. This does not apply when flattening, and does not combine
+    /// with [`CopyBuilder::delete_extraneous`], which would delete renamed entries.
+    pub fn with_rename<F>(self, rename: F) -> CopyBuilder
+    where
+        F: Fn(&Path) -> Option<PathBuf> + Send + Sync + 'static,
+    {
+        CopyBuilder {
+            rename: Some(Callback::new(Arc::new(rename))),
+            ..self
+        }
+    }
+
+    /// Write files whose path relative to the source matches `glob` compressed with `algo`, as
+    /// `<name>.gz` or `<name>.zst` (off by default), e.g. `usr/share/doc/**` to save space. The
+    /// first matching rule wins. The system using the destination must decompress these files
+    /// itself. This does not apply when flattening.
+    ///
+    /// The stats count the compressed size, and the origin manifest and plan the compressed
+    /// name. Compressed files are recopied whenever the overwrite options compare sizes or
+    /// contents, as these always differ from the source.
+    pub fn compress_matching(self, glob: &str, algo: Compression) -> CopyBuilder {
+        let mut compress = self.compress.clone();
+        compress.push((glob.trim_start_matches('/').to_owned(), algo));
+        CopyBuilder { compress, ..self }
+    }
+
+    /// A [`Relativizer`] for the destination that knows about the remap rules, to relativize the
+    /// symlinks of the copy afterwards
+    pub fn relativizer(&self) -> Relativizer {
+        self.remaps.iter().fold(
+            Relativizer::new(&self.destination),
+            |relativizer, (from, to)| relativizer.with_remap(from, to),
+        )
+    }
+
+    /// Only walk these directories of the source, given relative to it, instead of the whole
+    /// source (off by default). Entries keep their path relative to the source in the
+    /// destination, and the filters still apply.
+    ///
+    /// Unlike include paths, this never looks at the rest of the source, which avoids walking
+    /// huge irrelevant trees such as `/proc` when the source is `/`. The roots should not overlap.
+    pub fn with_roots<I: IntoIterator<Item = PathBuf>>(self, roots: I) -> CopyBuilder {
+        let mut all_roots = self.roots.clone();
+        all_roots.extend(
+            roots
+                .into_iter()
+                .map(|root| root.strip_prefix("/").unwrap_or(&root).to_path_buf()),
+        );
+        CopyBuilder {
+            roots: all_roots,
+            ..self
+        }
+    }
+
+    /// Copy every regular file that passes the filters directly into the destination root
+    /// instead of mirroring the source tree (off by default), e.g. to collect all shared
+    /// objects into a single directory.
+    ///
+    /// Symlinks are replaced by the file they resolve to inside the source, and skipped if they
+    /// do not resolve to a regular file. Files with the same name are handled according to
+    /// [`CopyBuilder::on_collision`]. Remaps do not apply, and this does not combine with
+    /// [`CopyBuilder::delete_extraneous`], which would delete everything copied.
+    pub fn flatten(self, flatten: bool) -> CopyBuilder {
+        CopyBuilder { flatten, ..self }
+    }
+
+    /// How to handle files with the same name when flattening or renaming (`Collision::Error` by
+    /// default).
+    ///
+    /// `Collision::Error` fails the copy, `Collision::Skip` keeps the first file and
+    /// `Collision::Rename` copies later ones under a numbered name. `Collision::KeepNewest` keeps
+    /// the file modified last, overwriting an older one already copied, which suits merging
+    /// overlapping library directories with [`CopyBuilder::with_roots`].
+    pub fn on_collision(self, on_collision: Collision) -> CopyBuilder {
+        CopyBuilder {
+            on_collision,
+            ..self
+        }
+    }
+
+    /// Copy into a fresh staging directory and only rename it to the destination once the copy is
+    /// complete, so the destination is never seen half copied (off by default). An existing
+    /// destination is replaced as a whole, including anything in it that is not in the source.
+    ///
+    /// The staging directory is created next to the destination, unless set with
+    /// [`CopyBuilder::with_temp_dir`], and removed if the copy fails.
+    pub fn atomic(self, atomic: bool) -> CopyBuilder {
+        CopyBuilder { atomic, ..self }
+    }
+
+    /// Create the staging directory of an [atomic](CopyBuilder::atomic) copy in `dir` instead of
+    /// next to the destination, e.g. on a larger volume. It must be on the same filesystem as the
+    /// destination for the final rename to succeed, otherwise a warning is logged up front and
+    /// the copy fails with [`CopyError::CrossDevice`] once complete.
+    pub fn with_temp_dir<P: AsRef<Path>>(self, dir: P) -> CopyBuilder {
+        CopyBuilder {
+            temp_dir: Some(dir.as_ref().to_path_buf()),
+            ..self
+        }
+    }
+
+    /// Write a manifest to `path` mapping every file, symlink and special file in the destination
+    /// back to its source, one `<destination relative path>\t<absolute source path>` line each
+    /// (off by default). Entries left alone because they are up to date are included, and
+    /// remapped and flattened entries are recorded with their actual source.
+    pub fn with_origin_manifest<P: AsRef<Path>>(self, path: P) -> CopyBuilder {
+        CopyBuilder {
+            origin_manifest: Some(path.as_ref().to_path_buf()),
+            ..self
+        }
+    }
+
+    /// Before copying anything, hash every regular file the filters select in the source and
+    /// compare them against the `sha256sum` manifest at `path` (off by default), e.g. to catch
+    /// a tampered or drifted source. Paths in the manifest are relative to the source, as
+    /// written by running `sha256sum` in it.
+    ///
+    /// Fails with [`CopyError::ManifestMismatch`] listing every file whose hash differs, every
+    /// file of the manifest that would not be copied and every file that would be copied but is
+    /// not in the manifest.
+    pub fn verify_source_manifest<P: AsRef<Path>>(self, path: P) -> CopyBuilder {
+        CopyBuilder {
+            source_manifest: Some(path.as_ref().to_path_buf()),
+            ..self
+        }
+    }
+
+    /// Keep at most `max` files open at once while copying, counting both the source and the
+    /// destination of a copy, to avoid running out of file descriptors (half the soft
+    /// `RLIMIT_NOFILE` limit at the time the builder is created by default). Copies wait for
+    /// others to finish once the limit is reached. The limit is shared by clones of the builder.
+    pub fn with_max_open_files(self, max: usize) -> CopyBuilder {
+        CopyBuilder {
+            open_files: Arc::new(Semaphore::new(max.max(1))),
+            ..self
+        }
+    }
+
+    /// Write files, symlinks and directories through `backend` instead of `std::fs`
+    pub fn with_backend(self, backend: impl CopyBackend + 'static) -> CopyBuilder {
+        CopyBuilder {
+            backend: Arc::new(backend),
+            ..self
+        }
+    }
+
+    /// Execute the copy operation
+    pub fn run(&self) -> Result<(), CopyError> {
+        self.run_with_stats().map(|_| ())
+    }
+
+    /// Check that no options conflict with each other, failing with
+    /// [`CopyError::ConflictingOptions`] for the first pair that does. Copies and plans check this
+    /// before touching anything.
+    ///
+    /// Flattening does not combine with [`CopyBuilder::delete_extraneous`], which would delete
+    /// every flattened file, nor with [`CopyBuilder::with_rename`] and
+    /// [`CopyBuilder::overlay_against`], which only apply to copies mirroring the source tree.
+    /// Renaming does not combine with [`CopyBuilder::delete_extraneous`] either, which would
+    /// delete every renamed entry, and [`CopyBuilder::with_fixed_mtime`] does not combine with
+    /// [`CopyBuilder::preserve_timestamps`].
+    pub fn validate(&self) -> Result<(), CopyError> {
+        let conflicts = [
+            (
+                self.flatten && self.delete_extraneous,
+                "flatten",
+                "delete_extraneous",
+                "flattened files have no counterpart in the source and would be deleted",
+            ),
+            (
+                self.flatten && self.rename.is_some(),
+                "flatten",
+                "with_rename",
+                "flattened files are not renamed",
+            ),
+            (
+                self.flatten && self.overlay_base.is_some(),
+                "flatten",
+                "overlay_against",
+                "a flattened copy cannot be compared with an overlay base",
+            ),
+            (
+                self.fixed_mtime.is_some() && self.preserve_timestamps,
+                "with_fixed_mtime",
+                "preserve_timestamps",
+                "every entry cannot have both the fixed times and those of its source",
+            ),
+            (
+                self.rename.is_some() && self.delete_extraneous,
+                "with_rename",
+                "delete_extraneous",
+                "renamed entries have no counterpart in the source and would be deleted",
+            ),
+            (
+                self.follow_symlinks && self.preserve_hardlinks,
+                "follow_symlinks",
+                "preserve_hardlinks",
+                "a file reached through symlinks cannot be told apart from hardlinks to it",
+            ),
+            (
+                self.reflink != ReflinkMode::Never && self.preserve_hardlinks,
+                "reflink",
+                "preserve_hardlinks",
+                "a file cannot both share the inode of another copy and only its data blocks",
+            ),
+        ];
+        match conflicts.into_iter().find(|(conflict, ..)| *conflict) {
+            Some((_, first, second, reason)) => Err(CopyError::ConflictingOptions {
+                first,
+                second,
+                reason,
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Execute the copy operation, returning statistics about what was done
+    pub fn run_with_stats(&self) -> Result<CopyStats, CopyError> {
+        let (stats, failures) = self.run_with_failures()?;
+        match failures.into_iter().next() {
+            Some((_, e)) => Err(e),
+            None => Ok(stats),
+        }
+    }
+
+    /// Execute the copy operation, carrying on past entries that cannot be copied even without
+    /// [`CopyBuilder::continue_on_error`], and return them with why. Only fails for errors that
+    /// stop the copy as a whole, such as a missing source or conflicting options.
+    pub fn run_collect(&self) -> Result<Vec<(PathBuf, std::io::Error)>, std::io::Error> {
+        let copier = CopyBuilder {
+            continue_on_error: true,
+            ..self.clone()
+        };
+        let (_, failures) = copier.run_with_failures()?;
+        Ok(failures
+            .into_iter()
+            .map(|(path, e)| (path, e.into()))
+            .collect())
+    }
+
+    /// Execute the copy operation, returning statistics about what was done and the source
+    /// entries that could not be copied with why, see [`CopyBuilder::continue_on_error`]. Without
+    /// it, the first failure stops the copy and is returned as the error.
+    pub fn run_with_failures(&self) -> Result<(CopyStats, Vec<(PathBuf, CopyError)>), CopyError> {
+        self.validate()?;
+        if self.dry_run {
+            return Ok((self.dry_run_stats()?, vec![]));
+        }
+        if self.atomic {
+            return self.run_atomic();
+        }
+        let start = Instant::now();
+        self.check_source()?;
+        self.check_overlap()?;
+        self.verify_source()?;
+        if !self.destination.is_dir() {
+            debug!("MKDIR {:?}", &self.destination);
+            self.backend.create_dir(&self.destination)?;
+        }
+        let abs_source = self.source.canonicalize()?;
+        let abs_dest = self.destination.canonicalize()?;
+        debug!(
+            "Building copy operation: SRC {} DST {}",
+            abs_source.display(),
+            abs_dest.display()
+        );
+
+        let mut matched_includes = vec![false; self.filters.includes().count()];
+        let mut run = RunState {
+            manifest: match &self.origin_manifest {
+                Some(path) => Some(BufWriter::new(std::fs::File::create(path)?)),
+                None => None,
+            },
+            ..RunState::default()
+        };
+        let counts = Rc::new(WalkCounts::default());
+        let decisions = self.decisions(abs_source.clone(), abs_dest.clone(), counts.clone())?;
+        std::thread::scope(|scope| -> Result<(), CopyError> {
+            let pool = (self.threads > 1).then(|| {
+                Pool::new(scope, self.threads, |(entry, dest_entry, decision)| {
+                    self.apply_entry(entry, dest_entry, decision)
+                })
+            });
+            // Hardlinks wait for the copy of their target, which may still be running
+            let mut hardlinks = vec![];
+            // Files and bytes handed to the pool but not finished, counted against the limits
+            let (mut pending_files, mut pending_bytes) = (0, 0);
+            // Reported once the files being copied are done, so the count is exact
+            let mut exceeded = None;
+            for decision in decisions {
+                if let Some(timeout) = self.timeout {
+                    let elapsed = start.elapsed();
+                    if elapsed > timeout {
+                        return Err(CopyError::TimedOut { elapsed });
+                    }
+                }
+                let (entry, dest_entry, decision) = decision?;
+                for (include, matched) in self.filters.includes().zip(matched_includes.iter_mut()) {
+                    *matched |= self.filters.matches(
+                        include,
+                        &abs_source,
+                        entry.path(),
+                        entry.file_type().is_dir(),
+                    );
+                }
+
+                if let Decision::CopyFile { len, .. } = decision {
+                    let files_copied = run.files_copied + pending_files;
+                    let bytes_copied = run.stats.bytes_copied + pending_bytes;
+                    if let Some(max) = self.max_files.filter(|max| files_copied + 1 > *max) {
+                        exceeded = Some(Limit::Files(max));
+                    } else if let Some(max) =
+                        self.max_total_bytes.filter(|max| bytes_copied + len > *max)
+                    {
+                        exceeded = Some(Limit::TotalBytes(max));
+                    }
+                    if exceeded.is_some() {
+                        break;
+                    }
+                }
+                let Some(pool) = &pool else {
+                    let applied = self.apply_entry(entry, dest_entry, decision);
+                    self.finish(&mut run, &abs_source, &abs_dest, applied)?;
+                    continue;
+                };
+                match decision {
+                    Decision::Hardlink { .. } => hardlinks.push((entry, dest_entry, decision)),
+                    Decision::CopyFile { len, .. } => {
+                        pending_files += 1;
+                        pending_bytes += len;
+                        pool.submit((entry, dest_entry, decision));
+                    }
+                    Decision::Symlink | Decision::Special => {
+                        pool.submit((entry, dest_entry, decision))
+                    }
+                    decision => {
+                        let applied = self.apply_entry(entry, dest_entry, decision);
+                        self.finish(&mut run, &abs_source, &abs_dest, applied)?;
+                    }
+                }
+                for applied in pool.finished() {
+                    if let Some(len) = applied.len {
+                        pending_files -= 1;
+                        pending_bytes -= len;
+                    }
+                    self.finish(&mut run, &abs_source, &abs_dest, applied)?;
+                }
+            }
+            if let Some(pool) = pool {
+                for applied in pool.join() {
+                    self.finish(&mut run, &abs_source, &abs_dest, applied)?;
+                }
+            }
+            if let Some(limit) = exceeded {
+                let copied = match limit {
+                    Limit::Files(_) => run.files_copied,
+                    Limit::TotalBytes(_) => run.stats.bytes_copied,
+                };
+                return Err(CopyError::LimitExceeded { limit, copied });
+            }
+            for (entry, dest_entry, decision) in hardlinks {
+                let applied = self.apply_entry(entry, dest_entry, decision);
+                self.finish(&mut run, &abs_source, &abs_dest, applied)?;
+            }
+            Ok(())
+        })?;
+        let RunState {
+            mut stats,
+            failures,
+            mut written_dirs,
+            dirs,
+            dir_permissions,
+            mut manifest,
+            ..
+        } = run;
+        if let Some(manifest) = &mut manifest {
+            manifest.flush()?;
+        }
+        stats.walk_errors = counts.errors.get();
+        stats.files_skipped += counts.pruned.get();
+        if let Some(more) = stats
+            .external_symlinks
+            .len()
+            .checked_sub(EXTERNAL_SYMLINK_WARNINGS)
+            .filter(|more| *more > 0)
+        {
+            warn!("{} more symlinks point outside of the source", more);
+        }
+
+        stats.unmatched_includes = self
+            .filters
+            .includes()
+            .zip(matched_includes)
+            .filter(|(_, matched)| !matched)
+            .map(|(include, _)| include.to_string())
+            .collect();
+        for include in &stats.unmatched_includes {
+            warn!("Include {} did not match anything", include);
+        }
+
+        if self.delete_extraneous {
+            stats.deleted = self.delete_extraneous_entries(&abs_source, &abs_dest)?;
+        }
+
+        // Done after deleting, which would remove the markers as they have no source counterpart
+        stats.whiteouts = self.write_whiteouts(&abs_source, &abs_dest)?;
+
+        // Done last, as the permissions of the source may not allow writing the children
+        for (source, dest_entry) in dir_permissions.iter().rev() {
+            self.copy_permissions(source, dest_entry)?;
+            if let Some((_, dir_mode)) = self.min_mode {
+                add_mode(dest_entry, dir_mode)?;
+            }
+        }
+        if self.copy_root_metadata {
+            debug!("CHMOD {}", abs_dest.display());
+            std::fs::set_permissions(&abs_dest, abs_source.metadata()?.permissions())?;
+            if let Some((_, dir_mode)) = self.min_mode {
+                add_mode(&abs_dest, dir_mode)?;
+            }
+        }
+
+        // Writing a directory's children updates its times, so set them once everything is done
+        for (source, dir) in dirs.iter().filter(|(_, dir)| dir.is_dir()) {
+            self.copy_times(source, dir)?;
+        }
+
+        if let Some(algo) = self.tree_hash {
+            stats.tree_hash = Some(checksum::tree_hash(&abs_dest, algo)?);
+        }
+
+        if self.sync != SyncMode::None {
+            written_dirs.insert(abs_dest.clone());
+            for dir in written_dirs.iter().filter(|dir| dir.is_dir()) {
+                debug!("SYNC {}", dir.display());
+                sync_path(dir)?;
+            }
+        }
+
+        stats.elapsed = start.elapsed();
+        Ok((stats, failures))
+    }
+
+    /// Carry out `decision` for the source `entry`, writing to `dest_entry`, keeping what
+    /// [`CopyBuilder::finish`] needs to account for it
+    fn apply_entry(&self, entry: DirEntry, dest_entry: PathBuf, decision: Decision) -> Applied {
+        let (origin, len) = match &decision {
+            Decision::CopyFile { resolved, len, .. } => (
+                resolved
+                    .clone()
+                    .unwrap_or_else(|| entry.path().to_path_buf()),
+                Some(*len),
+            ),
+            _ => (entry.path().to_path_buf(), None),
+        };
+        let done = self.apply(&entry, &dest_entry, decision);
+        Applied {
+            entry,
+            dest_entry,
+            origin,
+            len,
+            done,
+        }
+    }
+
+    /// Account for an entry carried out by [`CopyBuilder::apply_entry`], collecting its failure
+    /// or recording what was done
+    fn finish(
+        &self,
+        run: &mut RunState,
+        abs_source: &Path,
+        abs_dest: &Path,
+        applied: Applied,
+    ) -> Result<(), CopyError> {
+        let Applied {
+            entry,
+            dest_entry,
+            origin,
+            done,
+            ..
+        } = applied;
+        let done = match done {
+            Ok(done) => done,
+            Err(e) if self.continue_on_error => {
+                warn!("Failed to copy {}: {}", entry.path().display(), e);
+                run.failures.push((entry.into_path(), e));
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+        if self.sync == SyncMode::Full && !matches!(done, CopyDecision::Skipped(_)) {
+            if let Some(parent) = dest_entry.parent() {
+                run.written_dirs.insert(parent.to_path_buf());
+            }
+        }
+        if let CopyDecision::Copied(_) = done {
+            run.files_copied += 1;
+        }
+        self.record(&mut run.stats, abs_source, entry.path(), done)?;
+
+        if let Some(manifest) = &mut run.manifest {
+            if !entry.file_type().is_dir()
+                && self.filters.selects(abs_source, entry.path(), false)
+                && dest_entry.symlink_metadata().is_ok()
+            {
+                let rel_dest = dest_entry.strip_prefix(abs_dest).unwrap_or(&dest_entry);
+                writeln!(manifest, "{}\t{}", rel_dest.display(), origin.display())?;
+            }
+        }
+
+        if entry.file_type().is_dir()
+            && self.preserve_permissions
+            && entry.path() != abs_source
+            && done == CopyDecision::CreatedDir
+        {
+            run.dir_permissions
+                .push((entry.path().to_path_buf(), dest_entry.clone()));
+        }
+        if entry.file_type().is_dir() && (self.fixed_mtime.is_some() || self.preserve_timestamps) {
+            run.dirs.push((entry.path().to_path_buf(), dest_entry));
+        }
+        Ok(())
+    }
+
+    /// Carry out `decision` for the source `entry`, writing to `dest_entry`
+    fn apply(
+        &self,
+        entry: &DirEntry,
+        dest_entry: &Path,
+        decision: Decision,
+    ) -> Result<CopyDecision, CopyError> {
+        if self.dry_run {
+            return Ok(decision.dry_run());
+        }
+        // The copy a hardlink points to is missing if copying it failed, so copy the file again
+        let decision = match decision {
+            Decision::Hardlink { target, len } if target.symlink_metadata().is_err() => {
+                debug!("MISSING LINK TARGET {}", target.display());
+                Decision::CopyFile {
+                    len,
+                    resolved: None,
+                    compression: None,
+                }
+            }
+            decision => decision,
+        };
+        if let Some(on_overwrite) = &self.on_overwrite {
+            let source = match &decision {
+                Decision::CopyFile {
+                    resolved: Some(resolved),
+                    ..
+                } => Some(resolved.as_path()),
+                Decision::CopyFile { .. } | Decision::Hardlink { .. } => Some(entry.path()),
+                _ => None,
+            };
+            if let Some(source) = source.filter(|_| dest_entry.symlink_metadata().is_ok()) {
+                if !on_overwrite(source, dest_entry) {
+                    debug!("DECLINED {}", dest_entry.display());
+                    if let Some(on_skip) = &self.on_skip {
+                        on_skip(entry.path(), SkipReason::Declined);
+                    }
+                    return Ok(CopyDecision::Skipped(Some(SkipReason::Declined)));
+                }
+            }
+        }
+        match decision {
+            Decision::CopyFile {
+                len: total,
+                resolved,
+                compression,
+            } => {
+                let source = resolved.as_deref().unwrap_or(entry.path());
+                if self.force_overwrite_readonly {
+                    make_writable(dest_entry)?;
+                }
+                debug!("CP {} DST {}", source.display(), dest_entry.display());
+                self.create_parent(dest_entry)?;
+                let open_files = self.open_files.acquire(2);
+                let cloned = match compression {
+                    Some(_) => None,
+                    None => self.clone_file(source, dest_entry)?,
+                };
+                let len = match (cloned, compression, &self.on_progress) {
+                    (Some(len), _, on_progress) => {
+                        if let Some(on_progress) = on_progress {
+                            on_progress(source, len, total);
+                        }
+                        len
+                    }
+                    (None, Some(compression), on_progress) => {
+                        let len = compress::compress_file(source, dest_entry, compression)?;
+                        if let Some(on_progress) = on_progress {
+                            on_progress(source, total, total);
+                        }
+                        len
+                    }
+                    (None, None, Some(on_progress)) => {
+                        let progress = |copied| on_progress(source, copied, total);
+                        match self.buffer_size {
+                            Some(buffer_size) => self.backend.copy_file_buffered(
+                                source,
+                                dest_entry,
+                                buffer_size,
+                                &progress,
+                            )?,
+                            None => self
+                                .backend
+                                .copy_file_with_progress(source, dest_entry, &progress)?,
+                        }
+                    }
+                    (None, None, None) => match self.buffer_size {
+                        Some(buffer_size) => self.backend.copy_file_buffered(
+                            source,
+                            dest_entry,
+                            buffer_size,
+                            &|_| {},
+                        )?,
+                        None => self.backend.copy_file(source, dest_entry)?,
+                    },
+                };
+                drop(open_files);
+                self.copy_ownership(source, dest_entry)?;
+                self.copy_permissions(source, dest_entry)?;
+                if self.bypass_page_cache {
+                    evict_from_page_cache(source, false)?;
+                    evict_from_page_cache(dest_entry, true)?;
+                }
+                if let Some((file_mode, _)) = self.min_mode {
+                    add_mode(dest_entry, file_mode)?;
+                }
+                self.copy_special_bits(source, dest_entry)?;
+                if self.fixed_mtime.is_some() || self.preserve_timestamps {
+                    self.copy_times(source, dest_entry)?;
+                } else if self.resume {
+                    let mtime = FileTime::from_last_modification_time(&source.metadata()?);
+                    filetime::set_file_mtime(dest_entry, mtime)?;
+                }
+                if self.sync != SyncMode::None {
+                    sync_path(dest_entry)?;
+                }
+                // Immutable files cannot be modified at all, so this has to come last
+                if self.preserve_inode_flags {
+                    match flags::copy_inode_flags(source, dest_entry) {
+                        Err(e) if e.kind() == ErrorKind::PermissionDenied => warn!(
+                            "Not permitted to copy the inode flags of {}: {}",
+                            source.display(),
+                            e
+                        ),
+                        result => result?,
+                    }
+                }
+                Ok(CopyDecision::Copied(len))
+            }
+            Decision::Hardlink { target, len } => {
+                debug!("LN {} DST {}", target.display(), dest_entry.display());
+                self.create_parent(dest_entry)?;
+                if dest_entry.symlink_metadata().is_ok() {
+                    if self.force_overwrite_readonly {
+                        make_writable(dest_entry)?;
+                    }
+                    std::fs::remove_file(dest_entry)?;
+                }
+                std::fs::hard_link(&target, dest_entry)
+                    .map_err(|e| cross_device(e, &target, dest_entry))?;
+                Ok(CopyDecision::Hardlinked(len))
+            }
+            Decision::Symlink => {
+                debug!(
+                    "CP LNK {} DST {}",
+                    entry.path().display(),
+                    dest_entry.display()
+                );
+                let target = self.link_target(entry.path())?;
+                self.create_parent(dest_entry)?;
+                if dest_entry.symlink_metadata().is_ok() {
+                    if self.force_overwrite_readonly {
+                        make_writable(dest_entry)?;
+                    }
+                    std::fs::remove_file(dest_entry)?;
+                }
+                // Windows tells symlinks to directories apart, going by what the source resolves to
+                if entry.path().is_dir() {
+                    self.backend.create_dir_symlink(&target, dest_entry)?;
+                } else {
+                    self.backend.create_symlink(&target, dest_entry)?;
+                }
+                self.copy_ownership(entry.path(), dest_entry)?;
+                self.copy_times(entry.path(), dest_entry)?;
+                Ok(CopyDecision::Symlinked)
+            }
+            Decision::CreateDir => {
+                debug!("MKDIR {}", entry.path().display());
+                self.backend.create_dir(dest_entry)?;
+                self.copy_ownership(entry.path(), dest_entry)?;
+                if let Some((_, dir_mode)) = self.min_mode {
+                    add_mode(dest_entry, dir_mode)?;
+                }
+                self.copy_special_bits(entry.path(), dest_entry)?;
+                Ok(CopyDecision::CreatedDir)
+            }
+            Decision::Special => {
+                debug!(
+                    "MKNOD {} DST {}",
+                    entry.path().display(),
+                    dest_entry.display()
+                );
+                self.create_parent(dest_entry)?;
+                if dest_entry.symlink_metadata().is_ok() {
+                    std::fs::remove_file(dest_entry)?;
+                }
+                self.backend.create_special(entry.path(), dest_entry)?;
+                self.copy_ownership(entry.path(), dest_entry)?;
+                Ok(CopyDecision::Recreated)
+            }
+            Decision::ProtectedNewer => {
+                warn!(
+                    "Destination newer than source, not overwriting: {}",
+                    dest_entry.display()
+                );
+                Ok(CopyDecision::Skipped(Some(SkipReason::ProtectedNewer)))
+            }
+            Decision::Unsupported => {
+                eprintln!(
+                    "File {} has unhalded type {:?}, skipping",
+                    entry.path().display(),
+                    entry.file_type()
+                );
+                Ok(CopyDecision::Skipped(Some(SkipReason::UnsupportedType)))
+            }
+            Decision::Disallowed => Ok(CopyDecision::Skipped(Some(SkipReason::DisallowedType))),
+            Decision::Skip(reason) => Ok(CopyDecision::Skipped(reason)),
+        }
+    }
+
+    /// Copy the single entry at `src_rel`, relative to the source, applying the same filters and
+    /// overwrite checks as `run()`, and return what was done. Directories are created but not
+    /// copied recursively.
+    ///
+    /// This suits tools that watch the source and copy each changed file as it changes. Limits
+    /// and timeouts only apply to whole runs, nothing is recorded in the origin manifest, and
+    /// collisions between flattened files cannot be detected.
+    pub fn copy_one(&self, src_rel: &Path) -> Result<CopyDecision, CopyError> {
+        self.validate()?;
+        self.check_source()?;
+        self.check_overlap()?;
+        if !self.destination.is_dir() && !self.dry_run {
+            debug!("MKDIR {:?}", &self.destination);
+            self.backend.create_dir(&self.destination)?;
+        }
+        let abs_source = self.source.canonicalize()?;
+        let abs_dest = self
+            .destination
+            .canonicalize()
+            .or_else(|_| std::path::absolute(&self.destination))?;
+        let rel = src_rel.strip_prefix("/").unwrap_or(src_rel);
+        if self
+            .max_depth
+            .is_some_and(|max_depth| rel.components().count() > max_depth)
+        {
+            return Ok(CopyDecision::Skipped(None));
+        }
+
+        // Visit the entry and its ancestors like the walk would, stopping where it would prune
+        let accept = self.acceptor(&abs_source, &abs_dest, Rc::default())?;
+        let entry_at = |path: &Path| {
+            WalkDir::new(path)
+                .into_iter()
+                .next()
+                .expect("a walk yields its root")
+                .map_err(Error::from)
+        };
+        let mut path = abs_source.clone();
+        let mut entry = entry_at(&path)?;
+        for component in rel.components() {
+            path.push(component);
+            // Symlinks are followed inside the source, like the walk does
+            let resolved = match self.follow_symlinks {
+                true => {
+                    resolve_in_root(&abs_source, path.strip_prefix(&abs_source).unwrap_or(&path))?
+                }
+                false => Some(path.clone()),
+            };
+            let Some(resolved) = resolved else {
+                return Ok(CopyDecision::Skipped(Some(SkipReason::Dangling)));
+            };
+            entry = entry_at(&resolved)?;
+            if !accept(&entry, &path) {
+                return Ok(CopyDecision::Skipped(None));
+            }
+        }
+
+        let (entry, dest_entry, decision) = if self.flatten && path != abs_source {
+            self.decide_flattened(&abs_source, &abs_dest, entry, &path, &mut HashMap::new())?
+        } else {
+            let compress = self.compress_matchers()?;
+            self.decide_in_tree(
+                &abs_source,
+                &abs_dest,
+                entry,
+                &path,
+                &compress,
+                &mut HashMap::new(),
+            )?
+        };
+        if let (Some(on_skip), Some(reason)) = (&self.on_skip, decision.skip_reason()) {
+            on_skip(&path, reason);
+        }
+        let done = self.apply(&entry, &dest_entry, decision)?;
+        // Nothing is copied into the directory, so it can get its permissions right away
+        if done == CopyDecision::CreatedDir && entry.path() != abs_source {
+            self.copy_permissions(entry.path(), &dest_entry)?;
+            if let (true, Some((_, dir_mode))) = (self.preserve_permissions, self.min_mode) {
+                add_mode(&dest_entry, dir_mode)?;
+            }
+        }
+        if self.sync == SyncMode::Full && !matches!(done, CopyDecision::Skipped(_)) {
+            if let Some(parent) = dest_entry.parent() {
+                sync_path(parent)?;
+            }
+        }
+        Ok(done)
+    }
+
+    /// Bring the destination up to date with the source entries at the absolute paths in
+    /// `changed`, e.g. as reported by a file watcher, returning statistics about what was done.
+    ///
+    /// Each entry that still exists is copied like [`CopyBuilder::copy_one`] does, applying the
+    /// filters and overwrite options. The destination of each entry that no longer exists is
+    /// deleted, along with everything below it, unless the filters exclude the entry. Deleted
+    /// entries are not removed when flattening, as another file may have the same name, nor
+    /// compressed copies.
+    pub fn apply_changes<I: IntoIterator<Item = PathBuf>>(
+        &self,
+        changed: I,
+    ) -> Result<CopyStats, CopyError> {
+        let start = Instant::now();
+        let mut stats = CopyStats::default();
+        self.validate()?;
+        self.check_source()?;
+        let abs_source = self.source.canonicalize()?;
+        for path in changed {
+            let Some(rel) = path
+                .strip_prefix(&abs_source)
+                .or_else(|_| path.strip_prefix(&self.source))
+                .ok()
+            else {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "{} is not in the source {}",
+                        path.display(),
+                        abs_source.display()
+                    ),
+                )
+                .into());
+            };
+            if abs_source.join(rel).symlink_metadata().is_ok() {
+                let done = self.copy_one(rel)?;
+                self.record(&mut stats, &abs_source, &abs_source.join(rel), done)?;
+                continue;
+            }
+            if self.flatten || !self.matches_filters(rel) {
+                continue;
+            }
+            let abs_dest = self
+                .destination
+                .canonicalize()
+                .or_else(|_| std::path::absolute(&self.destination))?;
+            let dest_entry = match &self.rename {
+                Some(rename) => match rename(rel) {
+                    Some(renamed) => abs_dest.join(renamed.strip_prefix("/").unwrap_or(&renamed)),
+                    None => continue,
+                },
+                None => abs_dest.join(self.remap(rel)),
+            };
+            match dest_entry.symlink_metadata() {
+                Ok(_) if self.dry_run => {}
+                Ok(metadata) if metadata.is_dir() => {
+                    debug!("RMDIR {}", dest_entry.display());
+                    std::fs::remove_dir_all(&dest_entry)?;
+                }
+                Ok(_) => {
+                    debug!("RM {}", dest_entry.display());
+                    std::fs::remove_file(&dest_entry)?;
+                }
+                Err(_) => continue,
+            }
+            stats.deleted += 1;
+        }
+        stats.elapsed = start.elapsed();
+        Ok(stats)
+    }
+
+    /// Add what was done with the source entry at `path` to `stats`
+    fn record(
+        &self,
+        stats: &mut CopyStats,
+        abs_source: &Path,
+        path: &Path,
+        done: CopyDecision,
+    ) -> Result<(), CopyError> {
+        if let CopyDecision::Skipped(Some(_)) = done {
+            stats.files_skipped += 1;
+        }
+        match done {
+            CopyDecision::Copied(len) => {
+                stats.files_copied += 1;
+                stats.bytes_copied += len;
+                let extension = match path.extension() {
+                    Some(extension) => extension.to_string_lossy().into_owned(),
+                    None => "(none)".to_owned(),
+                };
+                let by_extension = stats.by_extension.entry(extension).or_default();
+                by_extension.files += 1;
+                by_extension.bytes += len;
+            }
+            CopyDecision::Hardlinked(len) => {
+                stats.hardlinks += 1;
+                stats.reclaimed_bytes += len;
+            }
+            CopyDecision::Skipped(Some(SkipReason::ProtectedNewer)) => stats.protected_newer += 1,
+            CopyDecision::Skipped(Some(SkipReason::DisallowedType)) => stats.disallowed += 1,
+            CopyDecision::CreatedDir => stats.dirs_created += 1,
+            CopyDecision::Symlinked => {
+                stats.symlinks_created += 1;
+                if let Some(target) = self.external_target(abs_source, path)? {
+                    if stats.external_symlinks.len() < EXTERNAL_SYMLINK_WARNINGS {
+                        warn!(
+                            "Symlink {} points to {}, outside of the source",
+                            path.display(),
+                            target.display()
+                        );
+                    }
+                    stats.external_symlinks.push((path.to_path_buf(), target));
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Determine if the entry at `path`, relative to the source, passes the include and exclude
+    /// paths and filters, i.e. if `run()` would consider copying it. Like the walk, this also
+    /// requires every directory above it to pass.
+    ///
+    /// Only the filters are checked: the ignore file, allowed file types and filesystem
+    /// boundaries are not. The entry does not have to exist, if it does not it is matched as a
+    /// file.
+    pub fn matches_filters(&self, path: &Path) -> bool {
+        let root = self
+            .source
+            .canonicalize()
+            .unwrap_or_else(|_| self.source.clone());
+        let rel = path.strip_prefix("/").unwrap_or(path);
+        self.passes_filters(&root, &root.join(rel))
+    }
+
+    /// Copy into a staging directory, then swap it in place of the destination
+    fn run_atomic(&self) -> Result<(CopyStats, Vec<(PathBuf, CopyError)>), CopyError> {
+        self.check_source()?;
+        self.check_overlap()?;
+        let abs_dest = std::path::absolute(&self.destination)?;
+        let (Some(parent), Some(name)) = (abs_dest.parent(), abs_dest.file_name()) else {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Cannot replace {} atomically", abs_dest.display()),
+            )
+            .into());
+        };
+        let temp_dir = self.temp_dir.as_deref().unwrap_or(parent);
+        if !temp_dir.is_dir() {
+            self.backend.create_dir(temp_dir)?;
+        }
+        if !parent.is_dir() {
+            self.backend.create_dir(parent)?;
+        }
+        if device(temp_dir)? != device(parent)? {
+            warn!(
+                "Temporary directory {} is on a different filesystem than {}, so the finished copy cannot be renamed into place",
+                temp_dir.display(),
+                abs_dest.display()
+            );
+        }
+
+        let suffix = format!("{}-{}", name.to_string_lossy(), std::process::id());
+        let staging = temp_dir.join(format!(".{}.tmp", suffix));
+        if staging.symlink_metadata().is_ok() {
+            std::fs::remove_dir_all(&staging)?;
+        }
+        let staged = CopyBuilder {
+            destination: staging.clone(),
+            atomic: false,
+            ..self.clone()
+        };
+        let (stats, failures) = match staged.run_with_failures() {
+            Ok(result) => result,
+            Err(e) => {
+                if let Err(e) = std::fs::remove_dir_all(&staging) {
+                    warn!("Failed to remove {}: {}", staging.display(), e);
+                }
+                return Err(e);
+            }
+        };
+
+        // A directory cannot be renamed over a non-empty one, so move the old one aside first
+        let old = parent.join(format!(".{}.old", suffix));
+        let replaced = abs_dest.symlink_metadata().is_ok();
+        if replaced {
+            debug!("MV {} {}", abs_dest.display(), old.display());
+            std::fs::rename(&abs_dest, &old).map_err(|e| cross_device(e, &abs_dest, &old))?;
+        }
+        debug!("MV {} {}", staging.display(), abs_dest.display());
+        if let Err(e) = std::fs::rename(&staging, &abs_dest) {
+            if let Err(e) = std::fs::remove_dir_all(&staging) {
+                warn!("Failed to remove {}: {}", staging.display(), e);
+            }
+            if replaced {
+                std::fs::rename(&old, &abs_dest)?;
+            }
+            return Err(cross_device(e, &staging, &abs_dest));
+        }
+        if replaced {
+            std::fs::remove_dir_all(&old)?;
+        }
+        if self.sync == SyncMode::Full {
+            sync_path(parent)?;
+        }
+        Ok((stats, failures))
+    }
+
+    /// Give `dest_entry` the mapped owner and group of `source`, if preserving ownership
+    fn copy_ownership(&self, source: &Path, dest_entry: &Path) -> Result<(), CopyError> {
+        if !self.preserve_ownership {
+            return Ok(());
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            let metadata = source.symlink_metadata()?;
+            let uid = match &self.uid_map {
+                Some(uid_map) => uid_map(metadata.uid()),
+                None => metadata.uid(),
+            };
+            let gid = match &self.gid_map {
+                Some(gid_map) => gid_map(metadata.gid()),
+                None => metadata.gid(),
+            };
+            debug!("CHOWN {}:{} {}", uid, gid, dest_entry.display());
+            // lchown changes a symlink itself rather than its target
+            std::os::unix::fs::lchown(dest_entry, Some(uid), Some(gid)).map_err(|e| {
+                if e.kind() != ErrorKind::PermissionDenied {
+                    return e;
+                }
+                Error::new(
+                    ErrorKind::PermissionDenied,
+                    format!(
+                        "Not permitted to change the owner of {} to {}:{}, which requires \
+                         CAP_CHOWN: {}",
+                        dest_entry.display(),
+                        uid,
+                        gid,
+                        e
+                    ),
+                )
+            })?;
+        }
+        #[cfg(not(unix))]
+        let _ = (source, dest_entry);
+        Ok(())
+    }
+
+    /// Give `dest_entry` the fixed times, or those of `source` if preserving them. Symlinks get
+    /// the times of the link itself rather than its target.
+    fn copy_times(&self, source: &Path, dest_entry: &Path) -> Result<(), CopyError> {
+        let (atime, mtime) = match self.fixed_mtime {
+            Some(mtime) => {
+                let mtime = FileTime::from_system_time(mtime);
+                (mtime, mtime)
+            }
+            None if self.preserve_timestamps => {
+                let metadata = source.symlink_metadata()?;
+                (
+                    FileTime::from_last_access_time(&metadata),
+                    FileTime::from_last_modification_time(&metadata),
+                )
+            }
+            None => return Ok(()),
+        };
+        filetime::set_symlink_file_times(dest_entry, atime, mtime)?;
+        Ok(())
+    }
+
+    /// Clone `source` to `dest_entry` as set by [`CopyBuilder::reflink`], returning its length,
+    /// or `None` if it is to be copied instead
+    fn clone_file(&self, source: &Path, dest_entry: &Path) -> Result<Option<u64>, CopyError> {
+        if self.reflink == ReflinkMode::Never {
+            return Ok(None);
+        }
+        match self.backend.reflink_file(source, dest_entry) {
+            Ok(len) => Ok(Some(len)),
+            Err(e) if self.reflink == ReflinkMode::Auto && reflink_unsupported(&e) => {
+                debug!("NO REFLINK {}: {}", dest_entry.display(), e);
+                Ok(None)
+            }
+            Err(e) => Err(Error::new(
+                e.kind(),
+                format!(
+                    "Could not reflink {} to {}: {}",
+                    source.display(),
+                    dest_entry.display(),
+                    e
+                ),
+            )
+            .into()),
+        }
+    }
+
+    /// Give `dest_entry` the permissions of `source`, if preserving them
+    fn copy_permissions(&self, source: &Path, dest_entry: &Path) -> Result<(), CopyError> {
+        if self.preserve_permissions {
+            debug!("CHMOD {}", dest_entry.display());
+            std::fs::set_permissions(dest_entry, source.metadata()?.permissions())?;
+        }
+        Ok(())
+    }
+
+    /// Give `dest_entry` exactly the setuid, setgid and sticky bits of `source`, if preserving them
+    fn copy_special_bits(&self, source: &Path, dest_entry: &Path) -> Result<(), CopyError> {
+        if !self.preserve_special_bits {
+            return Ok(());
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            const SPECIAL_BITS: u32 = 0o7000;
+            let bits = source.metadata()?.permissions().mode() & SPECIAL_BITS;
+            let mut permissions = dest_entry.metadata()?.permissions();
+            if permissions.mode() & SPECIAL_BITS != bits {
+                debug!("CHMOD {:o} {}", bits, dest_entry.display());
+                permissions.set_mode(permissions.mode() & !SPECIAL_BITS | bits);
+                std::fs::set_permissions(dest_entry, permissions)?;
+            }
+        }
+        #[cfg(not(unix))]
+        let _ = (source, dest_entry);
+        Ok(())
+    }
+
+    /// Create the parent directory of `dest_entry` if it is missing, as happens when the source
+    /// directory was walked but not selected itself
+    fn create_parent(&self, dest_entry: &Path) -> Result<(), CopyError> {
+        match dest_entry.parent() {
+            Some(parent) if !parent.is_dir() => {
+                debug!("MKDIR {}", parent.display());
+                self.backend.create_dir(parent)?;
+                if let Some((_, dir_mode)) = self.min_mode {
+                    add_mode(parent, dir_mode)?;
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Rewrite a source relative path through the first matching remap rule
+    fn remap(&self, rel: &Path) -> PathBuf {
+        remap_path(&self.remaps, rel)
+    }
+
+    /// The target to give the copy of the symlink `link`: absolute targets are remapped, and
+    /// relative ones re-expressed from where the link is copied to when normalizing them
+    fn link_target(&self, link: &Path) -> Result<PathBuf, CopyError> {
+        let target = read_link(link)?;
+        if !self.normalize_relative_symlinks || target.is_absolute() {
+            return Ok(self.remap_link_target(target));
+        }
+        let abs_source = self.source.canonicalize()?;
+        let Ok(rel_link) = link.strip_prefix(&abs_source) else {
+            return Ok(target);
+        };
+        // Resolve the target lexically, as `..` after a symlinked directory cannot be followed
+        // to the same place in the destination anyway
+        let mut resolved = rel_link.parent().unwrap_or(Path::new("")).to_path_buf();
+        for component in target.components() {
+            match component {
+                std::path::Component::ParentDir if !resolved.pop() => return Ok(target),
+                std::path::Component::ParentDir | std::path::Component::CurDir => {}
+                component => resolved.push(component),
+            }
+        }
+        let (Some(dest_link), Some(dest_target)) =
+            (self.dest_rel(rel_link), self.dest_rel(&resolved))
+        else {
+            return Ok(target);
+        };
+        let dest_parent = dest_link.parent().unwrap_or(Path::new(""));
+        Ok(match pathdiff::diff_paths(&dest_target, dest_parent) {
+            Some(normalized) if normalized.as_os_str().is_empty() => PathBuf::from("."),
+            Some(normalized) => normalized,
+            None => target,
+        })
+    }
+
+    /// The destination relative path the source relative path `rel` is copied to, or `None` if
+    /// it is renamed away
+    fn dest_rel(&self, rel: &Path) -> Option<PathBuf> {
+        match &self.rename {
+            Some(rename) => rename(rel).map(|renamed| {
+                renamed
+                    .strip_prefix("/")
+                    .map(Path::to_path_buf)
+                    .unwrap_or(renamed)
+            }),
+            None => Some(self.remap(rel)),
+        }
+    }
+
+    /// Rewrite an absolute symlink target through the remap rules
+    fn remap_link_target(&self, target: PathBuf) -> PathBuf {
+        match target.strip_prefix("/") {
+            Ok(rel) if !self.remaps.is_empty() => Path::new("/").join(self.remap(rel)),
+            _ => target,
+        }
+    }
+
+    /// Determine if the destination relative path `rel` is the copy of an entry in the source
+    fn has_source_counterpart(&self, abs_source: &Path, rel: &Path) -> bool {
+        // A compressed copy has the extension of its compression appended
+        let compressed = rel.extension().is_some_and(|extension| {
+            self.compress
+                .iter()
+                .any(|(_, compression)| extension == compression.extension())
+        });
+        if compressed && self.has_source_counterpart(abs_source, &rel.with_extension("")) {
+            return true;
+        }
+        let remapped = self
+            .remaps
+            .iter()
+            .filter_map(|(from, to)| rel.strip_prefix(to).ok().map(|rest| from.join(rest)));
+        std::iter::once(rel.to_path_buf())
+            .chain(remapped)
+            .any(|candidate| {
+                // Symlinks are followed inside the source when copying through them
+                self.remap(&candidate) == rel
+                    && match self.follow_symlinks {
+                        true => resolve_in_root(abs_source, &candidate).is_ok_and(|r| r.is_some()),
+                        false => abs_source.join(&candidate).symlink_metadata().is_ok(),
+                    }
+            })
+    }
+
+    /// Find the entries in `abs_dest` without a counterpart in `abs_source`, children before their
+    /// parents, skipping those protected from deletion
+    fn extraneous_entries(
+        &self,
+        abs_source: &Path,
+        abs_dest: &Path,
+    ) -> Result<Vec<DirEntry>, std::io::Error> {
+        let mut globs = GlobSetBuilder::new();
+        let mut prefixes = vec![];
+        for pattern in &self.mirror_protect {
+            let pattern = pattern.trim_start_matches('/');
+            if pattern.contains(['*', '?', '[', '{']) {
+                globs.add(Glob::new(pattern).map_err(|e| {
+                    Error::other(format!("Invalid mirror protect pattern {}: {}", pattern, e))
+                })?);
+            } else {
+                prefixes.push(PathBuf::from(pattern));
+            }
+        }
+        let globs = globs.build().map_err(|e| Error::other(e.to_string()))?;
+
+        // Pruning protected paths needs a pre-order walk, so collect the entries and reverse
+        // them to list children before their parents
+        let entries = self
+            .walkdir(abs_dest)
+            .min_depth(1)
+            .into_iter()
+            .filter_entry(|e| {
+                let Ok(rel) = e.path().strip_prefix(abs_dest) else {
+                    return false;
+                };
+                let protected = globs.is_match(rel) || prefixes.iter().any(|p| rel.starts_with(p));
+                if protected {
+                    debug!("PROTECT {}", e.path().display());
+                }
+                // Excluded entries were never copied, so they are not the copy's to delete
+                let source_path = abs_source.join(rel);
+                let is_dir = e.file_type().is_dir();
+                let excluded = !self.filters.selects(abs_source, &source_path, is_dir)
+                    && self.filters.rejection(abs_source, &source_path, is_dir)
+                        == SkipReason::Excluded;
+                if excluded {
+                    debug!("KEEP EXCLUDED {}", e.path().display());
+                }
+                // Never delete the source when it lives inside the destination
+                e.path() != abs_source && !protected && !excluded
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut extraneous = vec![];
+        for entry in entries.into_iter().rev() {
+            let rel = entry
+                .path()
+                .strip_prefix(abs_dest)
+                .map_err(|e| Error::other(format!("Could not strip prefix: {:?}", e)))?;
+            if !self.has_source_counterpart(abs_source, rel) {
+                extraneous.push(entry);
+            }
+        }
+        Ok(extraneous)
+    }
+
+    /// Delete the entries in `abs_dest` without a counterpart in `abs_source`, returning how many
+    /// were deleted
+    fn delete_extraneous_entries(
+        &self,
+        abs_source: &Path,
+        abs_dest: &Path,
+    ) -> Result<u64, std::io::Error> {
+        let mut deleted = 0;
+        for entry in self.extraneous_entries(abs_source, abs_dest)? {
+            debug!("RM {}", entry.path().display());
+            if entry.file_type().is_dir() {
+                // A directory still holding protected entries is kept
+                match std::fs::remove_dir(entry.path()) {
+                    Err(e) if e.kind() == ErrorKind::DirectoryNotEmpty => continue,
+                    result => result?,
+                }
+            } else {
+                std::fs::remove_file(entry.path())?;
+            }
+            deleted += 1;
+        }
+        Ok(deleted)
+    }
+
+    /// Create a whiteout marker in `abs_dest` for every entry of the overlay base, if any, that
+    /// has no counterpart in `abs_source`, returning how many were created. Below a directory
+    /// missing from the source, only the directory itself is whited out.
+    fn write_whiteouts(&self, abs_source: &Path, abs_dest: &Path) -> Result<u64, CopyError> {
+        let Some(base) = &self.overlay_base else {
+            return Ok(0);
+        };
+        let mut whiteouts = 0;
+        let mut walker = self.walkdir(base).min_depth(1).into_iter();
+        while let Some(entry) = walker.next() {
+            let entry = entry.map_err(Error::from)?;
+            let rel = entry
+                .path()
+                .strip_prefix(base)
+                .map_err(|e| Error::other(format!("Could not strip prefix: {:?}", e)))?;
+            if self.has_source_counterpart(abs_source, rel) {
+                continue;
+            }
+            if entry.file_type().is_dir() {
+                walker.skip_current_dir();
+            }
+            let mut name = std::ffi::OsString::from(".wh.");
+            name.push(entry.file_name());
+            let whiteout = abs_dest.join(rel).with_file_name(name);
+            debug!("WHITEOUT {}", whiteout.display());
+            self.create_parent(&whiteout)?;
+            std::fs::File::create(&whiteout)?;
+            whiteouts += 1;
+        }
+        Ok(whiteouts)
+    }
+
+    /// Every operation `run()` would perform, in walk order, without touching the destination.
+    /// Like [`CopyBuilder::plan`], but checks the options first and stops at the first error.
+    ///
+    /// The operations are computed from the same decisions `run()` carries out, so they cannot
+    /// disagree as long as neither tree changes in between.
+    pub fn run_dry(&self) -> Result<Vec<CopyOp>, CopyError> {
+        self.validate()?;
+        self.plan().collect()
+    }
+
+    /// The statistics of a [dry run](CopyBuilder::dry_run), recording every decision as if it
+    /// was carried out
+    fn dry_run_stats(&self) -> Result<CopyStats, CopyError> {
+        let start = Instant::now();
+        let mut stats = CopyStats::default();
+        self.check_source()?;
+        self.check_overlap()?;
+        let abs_source = self.source.canonicalize()?;
+        let abs_dest = self
+            .destination
+            .canonicalize()
+            .or_else(|_| std::path::absolute(&self.destination))?;
+        let counts = Rc::new(WalkCounts::default());
+        for decision in self.decisions(abs_source.clone(), abs_dest, counts.clone())? {
+            let (entry, _, decision) = decision?;
+            self.record(&mut stats, &abs_source, entry.path(), decision.dry_run())?;
+        }
+        stats.walk_errors = counts.errors.get();
+        stats.files_skipped += counts.pruned.get();
+        stats.elapsed = start.elapsed();
+        Ok(stats)
+    }
+
+    /// Count what `run()` would copy, without touching the destination.
+    ///
+    /// The same selection and overwrite rules as `run()` are applied, so the estimate matches
+    /// the work a subsequent run would do as long as neither tree changes in between.
+    pub fn estimate(&self) -> Result<CopyEstimate, CopyError> {
+        let mut estimate = CopyEstimate::default();
+        self.validate()?;
+        self.check_source()?;
+        self.check_overlap()?;
+        let abs_source = self.source.canonicalize()?;
+        let abs_dest = self
+            .destination
+            .canonicalize()
+            .or_else(|_| std::path::absolute(&self.destination))?;
+
+        for decision in self.decisions(abs_source, abs_dest, Rc::default())? {
+            let (_, dest_entry, decision) = decision?;
+            match decision {
+                Decision::CopyFile { len, .. } => {
+                    estimate.files += 1;
+                    estimate.total_bytes += len;
+                    if let Ok(metadata) = dest_entry.symlink_metadata() {
+                        if metadata.is_file() {
+                            estimate.replaced_bytes += metadata.len();
+                        }
+                    }
+                }
+                Decision::Symlink => estimate.symlinks += 1,
+                _ => {}
+            }
+        }
+
+        Ok(estimate)
+    }
+
+    /// Compare the source with the destination, without touching either, listing every entry
+    /// `run()` would add or update, every file it would leave alone and every entry mirror mode
+    /// would delete, sorted by path.
+    ///
+    /// The same selection and overwrite rules as `run()` are applied. Entries are reported as
+    /// removed whether or not [`CopyBuilder::delete_extraneous`] is set.
+    pub fn diff(&self) -> Result<Vec<DiffEntry>, CopyError> {
+        self.validate()?;
+        self.check_source()?;
+        self.check_overlap()?;
+        let abs_source = self.source.canonicalize()?;
+        let abs_dest = self
+            .destination
+            .canonicalize()
+            .or_else(|_| std::path::absolute(&self.destination))?;
+
+        let mut diff = vec![];
+        for decision in self.decisions(abs_source.clone(), abs_dest.clone(), Rc::default())? {
+            let (entry, dest_entry, decision) = decision?;
+            let exists = dest_entry.symlink_metadata().is_ok();
+            let change = match decision {
+                Decision::CopyFile {
+                    resolved: Some(ref resolved),
+                    ..
+                } if exists => Change::Updated(self.update_reason(resolved, &dest_entry)),
+                Decision::CopyFile { .. }
+                | Decision::Hardlink { .. }
+                | Decision::Symlink
+                | Decision::Special
+                    if exists =>
+                {
+                    Change::Updated(self.update_reason(entry.path(), &dest_entry))
+                }
+                Decision::CopyFile { .. }
+                | Decision::Hardlink { .. }
+                | Decision::Symlink
+                | Decision::Special
+                | Decision::CreateDir => Change::Added,
+                Decision::ProtectedNewer
+                | Decision::Skip(Some(SkipReason::UpToDate | SkipReason::InBase))
+                    if exists =>
+                {
+                    Change::Unchanged
+                }
+                _ => continue,
+            };
+            if let Ok(path) = dest_entry.strip_prefix(&abs_dest) {
+                if path != Path::new("") {
+                    diff.push(DiffEntry {
+                        path: path.to_path_buf(),
+                        change,
+                    });
+                }
+            }
+        }
+        if abs_dest.is_dir() {
+            for entry in self.extraneous_entries(&abs_source, &abs_dest)? {
+                if let Ok(path) = entry.path().strip_prefix(&abs_dest) {
+                    diff.push(DiffEntry {
+                        path: path.to_path_buf(),
+                        change: Change::Removed,
+                    });
+                }
+            }
+        }
+        diff.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(diff)
+    }
+
+    /// Why the existing `dest_entry` would be overwritten with `source`, checking the overwrite
+    /// options in the order `run()` applies them
+    fn update_reason(&self, source: &Path, dest_entry: &Path) -> UpdateReason {
+        if self.overwrite_if_newer && is_file_newer(source, dest_entry) {
+            UpdateReason::Newer
+        } else if self.overwrite_if_size_differs && is_filesize_different(source, dest_entry) {
+            UpdateReason::Size
+        } else if self.overwrite_if_content_differs && is_content_different(source, dest_entry) {
+            UpdateReason::Content
+        } else {
+            UpdateReason::Overwrite
+        }
+    }
+
+    /// Lazily compute the operations `run()` would perform, without touching the destination.
+    ///
+    /// Operations are yielded in walk order, so a directory always comes before its contents.
+    /// Errors, including conflicting options and those checking the source, are yielded in place of an operation.
+    pub fn plan(&self) -> impl Iterator<Item = Result<CopyOp, CopyError>> + '_ {
+        let decisions = self
+            .validate()
+            .and_then(|_| self.check_source())
+            .and_then(|_| self.check_overlap())
+            .and_then(|_| {
+                let abs_source = self.source.canonicalize()?;
+                let abs_dest = self
+                    .destination
+                    .canonicalize()
+                    .or_else(|_| std::path::absolute(&self.destination))?;
+                self.decisions(abs_source, abs_dest, Rc::default())
+            });
+        let (decisions, error) = match decisions {
+            Ok(decisions) => (Some(decisions), None),
+            Err(e) => (None, Some(Err(e))),
+        };
+        error
+            .into_iter()
+            .chain(decisions.into_iter().flatten().map(|decision| {
+                let (entry, destination, decision) = decision?;
+                let source = entry.into_path();
+                Ok(match decision {
+                    Decision::CopyFile { len, resolved, .. } => CopyOp::CopyFile {
+                        source: resolved.unwrap_or(source),
+                        destination,
+                        len,
+                    },
+                    Decision::Hardlink { target, .. } => CopyOp::Hardlink {
+                        source,
+                        destination,
+                        target,
+                    },
+                    Decision::Symlink => CopyOp::Symlink {
+                        target: self.link_target(&source)?,
+                        source,
+                        destination,
+                    },
+                    Decision::CreateDir => CopyOp::MakeDir { destination },
+                    Decision::Special => CopyOp::Special {
+                        source,
+                        destination,
+                    },
+                    Decision::ProtectedNewer
+                    | Decision::Unsupported
+                    | Decision::Disallowed
+                    | Decision::Skip(_) => CopyOp::Skip { source },
+                })
+            }))
+    }
+
+    /// Walk the source and decide what to do with each entry, yielding it along with its
+    /// destination path
+    fn decisions(
+        &self,
+        abs_source: PathBuf,
+        abs_dest: PathBuf,
+        counts: Rc<WalkCounts>,
+    ) -> Result<
+        impl Iterator<Item = Result<(DirEntry, PathBuf, Decision), CopyError>> + '_,
+        CopyError,
+    > {
+        // The source of each destination file when flattening or renaming
+        let mut taken: HashMap<PathBuf, PathBuf> = HashMap::new();
+        // The first copy of each source file with several links
+        let mut links: HashMap<(u64, u64), PathBuf> = HashMap::new();
+        let compress = self.compress_matchers()?;
+        Ok(self
+            .walk(&abs_source, &abs_dest, counts)?
+            .map(move |entry| {
+                let (entry, path) = entry?;
+                let (entry, dest_entry, decision) = if self.flatten && path != abs_source {
+                    self.decide_flattened(&abs_source, &abs_dest, entry, &path, &mut taken)?
+                } else {
+                    self.decide_in_tree(
+                        &abs_source,
+                        &abs_dest,
+                        entry,
+                        &path,
+                        &compress,
+                        &mut taken,
+                    )?
+                };
+                let decision = self.decide_hardlink(&entry, &dest_entry, decision, &mut links);
+                if let (Some(on_skip), Some(reason)) = (&self.on_skip, decision.skip_reason()) {
+                    on_skip(&path, reason);
+                }
+                Ok((entry, dest_entry, decision))
+            }))
+    }
+
+    /// Turn copying a file into hardlinking it to the copy of an earlier link to the same source
+    /// file in `links`, if preserving hardlinks
+    fn decide_hardlink(
+        &self,
+        entry: &DirEntry,
+        dest_entry: &Path,
+        decision: Decision,
+        links: &mut HashMap<(u64, u64), PathBuf>,
+    ) -> Decision {
+        let Some(inode) = shared_inode(entry).filter(|_| self.preserve_hardlinks) else {
+            return decision;
+        };
+        match decision {
+            Decision::CopyFile {
+                len,
+                resolved: None,
+                compression: None,
+            } => match links.get(&inode) {
+                Some(target) => Decision::Hardlink {
+                    target: target.clone(),
+                    len,
+                },
+                None => {
+                    links.insert(inode, dest_entry.to_path_buf());
+                    decision
+                }
+            },
+            // An up to date copy from an earlier run is as good as a fresh one
+            Decision::Skip(Some(SkipReason::UpToDate)) => {
+                links
+                    .entry(inode)
+                    .or_insert_with(|| dest_entry.to_path_buf());
+                decision
+            }
+            decision => decision,
+        }
+    }
+
+    /// Compile the globs of the compression rules
+    fn compress_matchers(&self) -> Result<Vec<(GlobMatcher, Compression)>, Error> {
+        self.compress
+            .iter()
+            .map(|(glob, compression)| {
+                let matcher = Glob::new(glob).map_err(|e| {
+                    Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("Invalid compression pattern {}: {}", glob, e),
+                    )
+                })?;
+                Ok((matcher.compile_matcher(), *compression))
+            })
+            .collect()
+    }
+
+    /// Decide what to do with an entry mirrored at its remapped or renamed path in the
+    /// destination, compressed according to the first of the `compress` rules matching it.
+    /// Renamed entries must not take a path an earlier entry in `taken` took.
+    fn decide_in_tree(
+        &self,
+        abs_source: &Path,
+        abs_dest: &Path,
+        entry: DirEntry,
+        path: &Path,
+        compress: &[(GlobMatcher, Compression)],
+        taken: &mut HashMap<PathBuf, PathBuf>,
+    ) -> Result<(DirEntry, PathBuf, Decision), CopyError> {
+        let rel_dest = path
+            .strip_prefix(abs_source)
+            .map_err(|e| Error::other(format!("Could not strip prefix: {:?}", e)))?;
+        if let Some(rename) = self.rename.as_ref().filter(|_| path != abs_source) {
+            let Some(renamed) = rename(rel_dest) else {
+                debug!("RENAME {} SKIP", entry.path().display());
+                let dest_entry = abs_dest.join(rel_dest);
+                return Ok((entry, dest_entry, Decision::Skip(Some(SkipReason::Renamed))));
+            };
+            let dest_entry = abs_dest.join(renamed.strip_prefix("/").unwrap_or(&renamed));
+            if entry.file_type().is_dir() {
+                let decision = self.decide(abs_source, &entry, path, &dest_entry)?;
+                return Ok((entry, dest_entry, decision));
+            }
+            return self.decide_unique(abs_source, entry, path, dest_entry, taken);
+        }
+        let mut dest_entry = abs_dest.join(self.remap(rel_dest));
+        let compression = compress
+            .iter()
+            .find(|(matcher, _)| entry.file_type().is_file() && matcher.is_match(rel_dest))
+            .map(|(_, compression)| *compression);
+        if let Some(compression) = compression {
+            let mut name = dest_entry.into_os_string();
+            name.push(".");
+            name.push(compression.extension());
+            dest_entry = PathBuf::from(name);
+        }
+        let mut decision = self.decide(abs_source, &entry, path, &dest_entry)?;
+        if let Decision::CopyFile {
+            compression: compress_as,
+            ..
+        } = &mut decision
+        {
+            *compress_as = compression;
+        }
+        Ok((entry, dest_entry, decision))
+    }
+
+    /// Decide what to do with an entry when flattening, placing it directly in the destination
+    /// root under a name no earlier entry in `taken` took
+    fn decide_flattened(
+        &self,
+        abs_source: &Path,
+        abs_dest: &Path,
+        entry: DirEntry,
+        path: &Path,
+        taken: &mut HashMap<PathBuf, PathBuf>,
+    ) -> Result<(DirEntry, PathBuf, Decision), CopyError> {
+        let dest_entry = abs_dest.join(path.file_name().unwrap_or(entry.file_name()));
+        if entry.file_type().is_dir() {
+            return Ok((entry, dest_entry, Decision::Skip(None)));
+        }
+        self.decide_unique(abs_source, entry, path, dest_entry, taken)
+    }
+
+    /// Decide what to do with an entry destined for `dest_entry`, unless an earlier entry in
+    /// `taken` already took that path, in which case [`CopyBuilder::on_collision`] applies
+    fn decide_unique(
+        &self,
+        abs_source: &Path,
+        entry: DirEntry,
+        path: &Path,
+        mut dest_entry: PathBuf,
+        taken: &mut HashMap<PathBuf, PathBuf>,
+    ) -> Result<(DirEntry, PathBuf, Decision), CopyError> {
+        let mut replace = false;
+        if let Some(first) = taken.get(&dest_entry) {
+            match self.on_collision {
+                Collision::Error => {
+                    return Err(CopyError::Collision {
+                        destination: dest_entry,
+                        first: first.clone(),
+                        second: entry.into_path(),
+                    })
+                }
+                Collision::Skip => {
+                    debug!("COLLISION {} SKIP", entry.path().display());
+                    return Ok((
+                        entry,
+                        dest_entry,
+                        Decision::Skip(Some(SkipReason::Collision)),
+                    ));
+                }
+                Collision::Rename => {
+                    let name = PathBuf::from(dest_entry.file_name().unwrap_or_default());
+                    let stem = name.file_stem().unwrap_or(name.as_os_str());
+                    let extension = name.extension();
+                    dest_entry = (1..)
+                        .map(|n| {
+                            let mut renamed = stem.to_os_string();
+                            renamed.push(format!("-{}", n));
+                            if let Some(extension) = extension {
+                                renamed.push(".");
+                                renamed.push(extension);
+                            }
+                            dest_entry.with_file_name(renamed)
+                        })
+                        .find(|renamed| !taken.contains_key(renamed))
+                        .expect("unbounded range");
+                    debug!(
+                        "COLLISION {} RENAME {}",
+                        entry.path().display(),
+                        dest_entry.display()
+                    );
+                }
+                Collision::KeepNewest => {
+                    let modified = |path: &Path| path.metadata().and_then(|m| m.modified()).ok();
+                    if modified(entry.path()) <= modified(first) {
+                        debug!("COLLISION {} SKIP OLDER", entry.path().display());
+                        return Ok((
+                            entry,
+                            dest_entry,
+                            Decision::Skip(Some(SkipReason::Collision)),
+                        ));
+                    }
+                    debug!(
+                        "COLLISION {} REPLACE {}",
+                        entry.path().display(),
+                        first.display()
+                    );
+                    replace = true;
+                }
+            }
+        }
+        let mut decision = self.decide(abs_source, &entry, path, &dest_entry)?;
+        // The destination holds the older file copied earlier, which is not up to date after all
+        if replace
+            && matches!(
+                decision,
+                Decision::Skip(Some(SkipReason::UpToDate)) | Decision::ProtectedNewer
+            )
+        {
+            decision = self.decide_copy(abs_source, &entry)?;
+        }
+        // Files left alone because they are up to date still take their name
+        if self.filters.selects(abs_source, path, false) {
+            taken.insert(dest_entry.clone(), entry.path().to_path_buf());
+        }
+        Ok((entry, dest_entry, decision))
+    }
+
+    /// Make sure the source exists and, if required, is not empty
+    fn check_source(&self) -> Result<(), CopyError> {
+        if !self.source.exists() {
+            return Err(CopyError::SourceMissing(self.source.clone()));
+        }
+        if self.error_on_empty && self.source.read_dir()?.next().is_none() {
+            return Err(CopyError::SourceEmpty(self.source.clone()));
+        }
+        Ok(())
+    }
+
+    /// Check the selected source files against the expected manifest, if any, see
+    /// [`CopyBuilder::verify_source_manifest`]
+    fn verify_source(&self) -> Result<(), CopyError> {
+        let Some(manifest) = &self.source_manifest else {
+            return Ok(());
+        };
+        let mut expected = checksum::read_manifest(manifest)?;
+        let abs_source = self.source.canonicalize()?;
+        let abs_dest = self
+            .destination
+            .canonicalize()
+            .or_else(|_| std::path::absolute(&self.destination))?;
+        let (mut changed, mut unexpected) = (vec![], vec![]);
+        for entry in self.walk(&abs_source, &abs_dest, Rc::default())? {
+            let (entry, path) = entry?;
+            if !entry.file_type().is_file() || !self.filters.selects(&abs_source, &path, false) {
+                continue;
+            }
+            let rel = path
+                .strip_prefix(&abs_source)
+                .map_err(|e| Error::other(format!("Could not strip prefix: {:?}", e)))?;
+            match expected.remove(rel) {
+                Some(hash) if checksum::sha256(entry.path())? != hash => {
+                    changed.push(rel.to_path_buf())
+                }
+                Some(_) => {}
+                None => unexpected.push(rel.to_path_buf()),
+            }
+        }
+        let mut missing: Vec<PathBuf> = expected.into_keys().collect();
+        if changed.is_empty() && missing.is_empty() && unexpected.is_empty() {
+            return Ok(());
+        }
+        changed.sort();
+        missing.sort();
+        unexpected.sort();
+        Err(CopyError::ManifestMismatch {
+            manifest: manifest.clone(),
+            changed,
+            missing,
+            unexpected,
+        })
+    }
+
+    /// Refuse to copy when the destination is the source or inside it through any path,
+    /// including symlinks and bind mounts, as the walk would then copy the destination into itself
+    fn check_overlap(&self) -> Result<(), CopyError> {
+        let source = Handle::from_path(&self.source)?;
+        // The destination is created by the copy, so resolve the deepest part that exists
+        let abs_dest = std::path::absolute(&self.destination)?;
+        let existing = match abs_dest.ancestors().find(|p| p.exists()) {
+            Some(existing) => existing.canonicalize()?,
+            None => return Ok(()),
+        };
+        for ancestor in existing.ancestors() {
+            let handle = Handle::from_path(ancestor)?;
+            if handle == source {
+                return Err(CopyError::DestinationInSource {
+                    source: self.source.clone(),
+                    destination: self.destination.clone(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Walk the source tree, or only its roots if any, pruning excluded and not included paths,
+    /// yielding every entry along with its path in the source. Directories that are not selected
+    /// themselves are still walked if an include may select something below them. Entries that
+    /// cannot be read are logged and counted in `counts`, as are pruned entries.
+    ///
+    /// When following symlinks, each one is resolved inside the source, like [`resolve_in_root`],
+    /// and replaced by the file or directory it resolves to, which keeps the path of the symlink.
+    /// Fails on the first symlink to a directory it is already in.
+    fn walk(
+        &self,
+        abs_source: &Path,
+        abs_dest: &Path,
+        counts: Rc<WalkCounts>,
+    ) -> Result<impl Iterator<Item = Result<(DirEntry, PathBuf), Error>> + '_, std::io::Error> {
+        let accept = Rc::new(self.acceptor(abs_source, abs_dest, counts.clone())?);
+        let max_depth = self.max_depth.unwrap_or(usize::MAX);
+        let roots = if self.roots.is_empty() {
+            vec![(abs_source.to_path_buf(), max_depth)]
+        } else {
+            // Still visit the source itself, so the destination root is handled as usual
+            let mut roots = vec![(abs_source.to_path_buf(), 0)];
+            for root in &self.roots {
+                let abs_root = abs_source.join(root);
+                if !abs_root.is_dir() {
+                    return Err(Error::new(
+                        ErrorKind::NotFound,
+                        format!("Root {} is not a directory", abs_root.display()),
+                    ));
+                }
+                // Roots are walked on their own, so the depth counts from the source
+                if let Some(below) = max_depth.checked_sub(root.components().count()) {
+                    roots.push((abs_root, below));
+                }
+            }
+            roots
+        };
+
+        // Walk `dir`, which is at `path` in the source, yielding each entry with its path there
+        let walk_dir = {
+            let accept = accept.clone();
+            move |dir: &Path, path: &Path, max_depth: usize| -> SourceWalk<'_> {
+                let accept = accept.clone();
+                let walker = self.walkdir(dir).max_depth(max_depth);
+                let (dir, path) = (dir.to_path_buf(), path.to_path_buf());
+                let in_source = move |entry: &DirEntry| match entry.path().strip_prefix(&dir) {
+                    Ok(rel) if rel != Path::new("") => path.join(rel),
+                    _ => path.clone(),
+                };
+                let in_source = Rc::new(in_source);
+                let filter = in_source.clone();
+                Box::new(
+                    walker
+                        .into_iter()
+                        .filter_entry(move |e| accept(e, &filter(e)))
+                        .map(move |e| e.map(|e| (in_source(&e), e))),
+                )
+            }
+        };
+        // The walks in progress, innermost last, each with the directory it walks
+        let mut walks: Vec<(SourceWalk<'_>, PathBuf)> = roots
+            .into_iter()
+            .rev()
+            .map(|(root, max_depth)| (walk_dir(&root, &root, max_depth), root))
+            .collect();
+        let abs_source = abs_source.to_path_buf();
+        let follow_symlinks = self.follow_symlinks;
+        Ok(std::iter::from_fn(move || loop {
+            let (walk, _) = walks.last_mut()?;
+            let (path, entry) = match walk.next() {
+                Some(Ok(next)) => next,
+                Some(Err(e)) => {
+                    warn!("Skipping what could not be read: {}", e);
+                    counts.errors.set(counts.errors.get() + 1);
+                    continue;
+                }
+                None => {
+                    walks.pop();
+                    continue;
+                }
+            };
+            if !follow_symlinks || !entry.path_is_symlink() {
+                return Some(Ok((entry, path)));
+            }
+            let rel = path.strip_prefix(&abs_source).unwrap_or(&path);
+            let resolved = match resolve_in_root(&abs_source, rel) {
+                Ok(Some(resolved)) => resolved,
+                Ok(None) => {
+                    debug!("DANGLING {}", path.display());
+                    counts.pruned.set(counts.pruned.get() + 1);
+                    if let Some(on_skip) = &self.on_skip {
+                        on_skip(&path, SkipReason::Dangling);
+                    }
+                    continue;
+                }
+                Err(e) => return Some(Err(e)),
+            };
+            if resolved.is_dir() {
+                let walked = walks.iter().map(|(_, dir)| dir.as_path());
+                if walked
+                    .chain(entry.path().parent())
+                    .any(|dir| dir.starts_with(&resolved))
+                {
+                    return Some(Err(Error::other(format!(
+                        "Symlink {} loops back to its ancestor {}",
+                        path.display(),
+                        resolved.display()
+                    ))));
+                }
+                let below = max_depth.saturating_sub(rel.components().count());
+                walks.push((walk_dir(&resolved, &path, below), resolved));
+                continue;
+            }
+            // The link was let through to be resolved, so check what it resolves to instead
+            match WalkDir::new(&resolved).into_iter().next() {
+                Some(Ok(target)) if accept(&target, &path) => return Some(Ok((target, path))),
+                Some(Ok(_)) => {}
+                Some(Err(e)) => {
+                    warn!("Skipping what could not be read: {}", e);
+                    counts.errors.set(counts.errors.get() + 1);
+                }
+                None => {}
+            }
+        }))
+    }
+
+    /// Build the predicate deciding which entries the walk of `abs_source` visits and descends
+    /// into, given each entry and its path in the source, reporting those it prunes to the skip
+    /// callback and counting them in `counts`. Symlinks that are followed are always let
+    /// through, as what they resolve to is only known once they are.
+    fn acceptor(
+        &self,
+        abs_source: &Path,
+        abs_dest: &Path,
+        counts: Rc<WalkCounts>,
+    ) -> Result<impl Fn(&DirEntry, &Path) -> bool, std::io::Error> {
+        let root = abs_source.to_path_buf();
+        let abs_dest = abs_dest.to_path_buf();
+        let filters = self.filters.clone();
+        let ignore = self.load_ignore_file(abs_source)?;
+        let root_device = match self.same_filesystem {
+            true => device(abs_source)?,
+            false => None,
+        };
+        let dirs_allowed = self
+            .allowed_types
+            .as_ref()
+            .is_none_or(|allowed| allowed.contains(&FileTypeKind::Dir));
+        let follow_symlinks = self.follow_symlinks;
+        let on_skip = self.on_skip.clone();
+        let skip = move |path: &Path, reason| {
+            counts.pruned.set(counts.pruned.get() + 1);
+            if let Some(on_skip) = &on_skip {
+                on_skip(path, reason);
+            }
+        };
+        Ok(move |e: &DirEntry, path: &Path| {
+            let is_dir = e.file_type().is_dir();
+            // Disallowed directories are still visited to be counted, but not descended into
+            if !dirs_allowed && path != root && path.parent() != Some(root.as_path()) {
+                return false;
+            }
+            if follow_symlinks && e.path_is_symlink() {
+                return true;
+            }
+            if root_device.is_some() && device(e.path()).ok().flatten() != root_device {
+                debug!("OTHER FILESYSTEM {}", path.display());
+                skip(path, SkipReason::OtherFilesystem);
+                return false;
+            }
+            let selected = path == root
+                || filters.selects(&root, path, is_dir)
+                || (is_dir && filters.may_select_below(path));
+            if let Some((ignore_path, ignore)) = &ignore {
+                if path == ignore_path || ignore.matched(path, is_dir).is_ignore() {
+                    debug!("IGNORE {}", path.display());
+                    skip(path, SkipReason::Ignored);
+                    return false;
+                }
+            }
+            let is_dest = path == abs_dest || e.path() == abs_dest;
+            if !selected && !is_dest {
+                skip(path, filters.rejection(&root, path, is_dir));
+            }
+            !is_dest && selected
+        })
+    }
+
+    /// Determine if `dest_entry` is a complete copy of the file `entry` according to its size and
+    /// modification time, see [`CopyBuilder::resume`]
+    fn is_complete(&self, entry: &DirEntry, dest_entry: &Path) -> bool {
+        let (Ok(source), Ok(dest)) = (entry.metadata(), dest_entry.symlink_metadata()) else {
+            return false;
+        };
+        let expected = match self.fixed_mtime {
+            Some(mtime) => FileTime::from_system_time(mtime),
+            None => FileTime::from_last_modification_time(&source),
+        };
+        dest.is_file()
+            && dest.len() == source.len()
+            && FileTime::from_last_modification_time(&dest) == expected
+    }
+
+    /// Determine if `entry` is unchanged from its counterpart in the overlay base, see
+    /// [`CopyBuilder::overlay_against`]. Always false without a base.
+    fn is_unchanged_in_base(&self, abs_source: &Path, entry: &DirEntry, path: &Path) -> bool {
+        let (Some(base), false) = (&self.overlay_base, self.flatten) else {
+            return false;
+        };
+        let Ok(rel) = path.strip_prefix(abs_source) else {
+            return false;
+        };
+        let base_entry = base.join(self.remap(rel));
+        let (Ok(source), Ok(based)) = (entry.metadata(), base_entry.symlink_metadata()) else {
+            return false;
+        };
+        if FileTypeKind::of(source.file_type()) != FileTypeKind::of(based.file_type()) {
+            false
+        } else if source.is_file() && self.overwrite_if_content_differs {
+            !is_content_different(entry.path(), &base_entry)
+        } else if source.is_file() {
+            source.len() == based.len() && source.modified().ok() == based.modified().ok()
+        } else if source.is_symlink() {
+            read_link(entry.path()).ok() == read_link(&base_entry).ok()
+        } else {
+            true
+        }
+    }
+
+    /// Determine if entries of `file_type` are copied
+    fn is_allowed(&self, file_type: std::fs::FileType) -> bool {
+        match &self.allowed_types {
+            Some(allowed) => {
+                FileTypeKind::of(file_type).is_some_and(|kind| allowed.contains(&kind))
+            }
+            None => true,
+        }
+    }
+
+    /// The target of the symlink `link` in `abs_source` if it points outside the source, see
+    /// [`CopyStats::external_symlinks`]
+    fn external_target(
+        &self,
+        abs_source: &Path,
+        link: &Path,
+    ) -> Result<Option<PathBuf>, CopyError> {
+        let target = read_link(link)?;
+        let external = if target.is_absolute() {
+            let rel = link
+                .strip_prefix(abs_source)
+                .map_err(|e| Error::other(format!("Could not strip prefix: {:?}", e)))?;
+            resolve_in_root(abs_source, rel)?.is_none()
+        } else {
+            let mut resolved = link.parent().unwrap_or(abs_source).to_path_buf();
+            target.components().any(|component| {
+                match component {
+                    std::path::Component::ParentDir => {
+                        resolved.pop();
+                    }
+                    component => resolved.push(component),
+                }
+                !resolved.starts_with(abs_source)
+            })
+        };
+        Ok(external.then_some(target))
+    }
+
+    /// Decide what to do with a symlink, checking whether it dangles if required
+    fn decide_symlink(&self, abs_source: &Path, entry: &DirEntry) -> Result<Decision, CopyError> {
+        let rel = entry
+            .path()
+            .strip_prefix(abs_source)
+            .map_err(|e| Error::other(format!("Could not strip prefix: {:?}", e)))?;
+        if self.links_to_excluded(abs_source, rel)? {
+            debug!("TARGET EXCLUDED {}", entry.path().display());
+            return Ok(Decision::Skip(Some(SkipReason::TargetExcluded)));
+        }
+        if self.dangling_symlinks == DanglingMode::Keep {
+            return Ok(Decision::Symlink);
+        }
+        if resolve_in_root(abs_source, rel)?.is_some() {
+            return Ok(Decision::Symlink);
+        }
+        match self.dangling_symlinks {
+            DanglingMode::Error => Err(CopyError::DanglingSymlink {
+                link: entry.path().to_path_buf(),
+                target: read_link(entry.path())?,
+            }),
+            _ => {
+                debug!("DANGLING {}", entry.path().display());
+                Ok(Decision::Skip(Some(SkipReason::Dangling)))
+            }
+        }
+    }
+
+    /// Determine if `path` below `root` and every directory above it pass the filters, see
+    /// [`CopyBuilder::matches_filters`]
+    fn passes_filters(&self, root: &Path, path: &Path) -> bool {
+        let rel = path.strip_prefix(root).unwrap_or(path);
+        let mut current = root.to_path_buf();
+        let mut components = rel.components().peekable();
+        while let Some(component) = components.next() {
+            current.push(component);
+            let last = components.peek().is_none();
+            // Everything above the entry has to be a directory
+            let is_dir = !last || current.symlink_metadata().is_ok_and(|m| m.is_dir());
+            if self.filters.selects(root, &current, is_dir) {
+                continue;
+            }
+            // A directory that is not selected is still walked if something below it may be
+            if last || !self.filters.may_select_below(&current) {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Determine if the symlink at `rel` is to be skipped because its target is filtered out, see
+    /// [`CopyBuilder::prune_symlinks_to_excluded`]
+    fn links_to_excluded(&self, abs_source: &Path, rel: &Path) -> Result<bool, Error> {
+        if !self.prune_symlinks_to_excluded {
+            return Ok(false);
+        }
+        Ok(resolve_in_root(abs_source, rel)?
+            .is_some_and(|resolved| !self.passes_filters(abs_source, &resolved)))
+    }
+
+    /// Decide what to do with a symlink when flattening: copy the regular file it resolves to
+    /// inside the source, if any
+    fn decide_flattened_symlink(
+        &self,
+        abs_source: &Path,
+        entry: &DirEntry,
+    ) -> Result<Decision, CopyError> {
+        let rel = entry
+            .path()
+            .strip_prefix(abs_source)
+            .map_err(|e| Error::other(format!("Could not strip prefix: {:?}", e)))?;
+        if self.links_to_excluded(abs_source, rel)? {
+            debug!("TARGET EXCLUDED {}", entry.path().display());
+            return Ok(Decision::Skip(Some(SkipReason::TargetExcluded)));
+        }
+        match resolve_in_root(abs_source, rel)? {
+            Some(resolved) if resolved.is_file() => Ok(Decision::CopyFile {
+                len: resolved.metadata()?.len(),
+                resolved: Some(resolved),
+                compression: None,
+            }),
+            _ => {
+                debug!("NOT A FILE {}", entry.path().display());
+                Ok(Decision::Skip(Some(SkipReason::NotAFile)))
+            }
+        }
+    }
+
+    /// Decide how to copy `entry`, which is not a directory, regardless of the destination
+    fn decide_copy(&self, abs_source: &Path, entry: &DirEntry) -> Result<Decision, CopyError> {
+        if entry.file_type().is_file() {
+            let len = entry.metadata().map(|m| m.len()).unwrap_or(0);
+            Ok(Decision::CopyFile {
+                len,
+                resolved: None,
+                compression: None,
+            })
+        } else if entry.file_type().is_symlink() && self.flatten {
+            self.decide_flattened_symlink(abs_source, entry)
+        } else if entry.file_type().is_symlink() {
+            self.decide_symlink(abs_source, entry)
+        } else if self.allowed_types.is_some() {
+            Ok(Decision::Special)
+        } else {
+            match self.special_files {
+                SpecialFiles::Skip => Ok(Decision::Unsupported),
+                SpecialFiles::Recreate => Ok(Decision::Special),
+                SpecialFiles::Error => Err(CopyError::SpecialFile(entry.path().to_path_buf())),
+            }
+        }
+    }
+
+    /// Create a `WalkDir` over `root` honoring the traversal options
+    fn walkdir(&self, root: &Path) -> WalkDir {
+        let walkdir = WalkDir::new(root);
+        if self.sorted {
+            walkdir.sort_by_file_name()
+        } else {
+            walkdir
+        }
+    }
+
+    /// Load the auto ignore file from the source root, if configured and present
+    fn load_ignore_file(
+        &self,
+        abs_source: &Path,
+    ) -> Result<Option<(PathBuf, Gitignore)>, std::io::Error> {
+        let Some(name) = &self.auto_ignore_file else {
+            return Ok(None);
+        };
+        let path = abs_source.join(name);
+        if !path.is_file() {
+            return Ok(None);
+        }
+        debug!("Using ignore file {}", path.display());
+        let mut builder = GitignoreBuilder::new(abs_source);
+        if let Some(e) = builder.add(&path) {
+            return Err(Error::other(format!(
+                "Could not read ignore file {}: {}",
+                path.display(),
+                e
+            )));
+        }
+        let ignore = builder.build().map_err(|e| {
+            Error::other(format!(
+                "Could not parse ignore file {}: {}",
+                path.display(),
+                e
+            ))
+        })?;
+        Ok(Some((path, ignore)))
+    }
+
+    /// Determine if the regular file `entry` is neither larger nor smaller than the size limits
+    fn within_size_limits(&self, entry: &DirEntry) -> bool {
+        if self.max_file_size.is_none() && self.min_file_size.is_none() {
+            return true;
+        }
+        let len = entry.metadata().map(|m| m.len()).unwrap_or(0);
+        self.max_file_size.is_none_or(|max| len <= max)
+            && self.min_file_size.is_none_or(|min| len >= min)
+    }
+
+    /// Decide what to do with a single source `entry` destined for `dest_entry`
+    fn decide(
+        &self,
+        abs_source: &Path,
+        entry: &DirEntry,
+        path: &Path,
+        dest_entry: &Path,
+    ) -> Result<Decision, CopyError> {
+        if !self.is_allowed(entry.file_type())
+            && path != abs_source
+            && self
+                .filters
+                .selects(abs_source, path, entry.file_type().is_dir())
+        {
+            debug!("DISALLOWED {}", entry.path().display());
+            return Ok(Decision::Disallowed);
+        }
+        if entry.path().symlink_metadata().is_ok() && !entry.file_type().is_dir() {
+            // the source exists, but isn't a directory
+
+            if !self.filters.selects(abs_source, path, false) {
+                debug!("EXCL {:?}", entry);
+                return Ok(Decision::Skip(Some(
+                    self.filters.rejection(abs_source, path, false),
+                )));
+            }
+
+            if self.is_unchanged_in_base(abs_source, entry, path) {
+                debug!("UNCHANGED {}", entry.path().display());
+                return Ok(Decision::Skip(Some(SkipReason::InBase)));
+            }
+
+            if entry.file_type().is_file() && !self.within_size_limits(entry) {
+                debug!("SIZE {}", entry.path().display());
+                return Ok(Decision::Skip(Some(SkipReason::OutsideSizeLimits)));
+            }
+
+            // Early out if target is present and overwrite is off
+            if !self.overwrite_all
+                && !self.resume
+                && dest_entry.symlink_metadata().is_ok()
+                && !self.overwrite_if_newer
+                && !self.overwrite_if_size_differs
+                && !self.overwrite_if_content_differs
+            {
+                return Ok(Decision::Skip(Some(SkipReason::UpToDate)));
+            }
+
+            if let Some(since) = self.modified_since {
+                let modified = entry.metadata().ok().and_then(|m| m.modified().ok());
+                if modified.is_some_and(|modified| modified < since) {
+                    debug!("Not modified since cutoff: {}", entry.path().display());
+                    return Ok(Decision::Skip(Some(SkipReason::NotModifiedSince)));
+                }
+            }
+
+            // Never clobber a destination that is newer than its source, whatever else is set
+            let dest_exists = dest_entry.symlink_metadata().is_ok();
+            if dest_exists
+                && self.protect_newer_destination
+                && is_file_newer(dest_entry, entry.path())
+            {
+                return Ok(Decision::ProtectedNewer);
+            }
+
+            if self.resume && entry.file_type().is_file() {
+                if self.is_complete(entry, dest_entry) {
+                    debug!("Already complete: {}", dest_entry.display());
+                    return Ok(Decision::Skip(Some(SkipReason::UpToDate)));
+                }
+                let len = entry.metadata().map(|m| m.len()).unwrap_or(0);
+                return Ok(Decision::CopyFile {
+                    len,
+                    resolved: None,
+                    compression: None,
+                });
+            }
+            if self.resume
+                && entry.file_type().is_symlink()
+                && !self.flatten
+                && read_link(dest_entry).is_ok_and(|dest_target| {
+                    self.link_target(entry.path())
+                        .is_ok_and(|target| target == dest_target)
+                })
+            {
+                debug!("Already complete: {}", dest_entry.display());
+                return Ok(Decision::Skip(Some(SkipReason::UpToDate)));
+            }
+
+            // File is not present: copy it in any case
+            if !dest_exists {
+                debug!(
+                    "Dest not present: CP {} DST {}",
+                    entry.path().display(),
+                    dest_entry.display()
+                );
+            }
+
+            // Overwrite when any of the enabled checks finds the destination outdated
+            if dest_exists
+                && (self.overwrite_if_newer
+                    || self.overwrite_if_size_differs
+                    || self.overwrite_if_content_differs)
+            {
+                match self.update_reason(entry.path(), dest_entry) {
+                    UpdateReason::Overwrite => {
+                        return Ok(Decision::Skip(Some(SkipReason::UpToDate)));
+                    }
+                    reason => debug!(
+                        "Source differs ({:?}): CP {} DST {}",
+                        reason,
+                        entry.path().display(),
+                        dest_entry.display()
+                    ),
+                }
+            }
+
+            self.decide_copy(abs_source, entry)
+        } else if entry.path().is_dir()
+            && !dest_entry.is_dir()
+            && !self.is_unchanged_in_base(abs_source, entry, path)
+            && (path == abs_source
+                || (!self.flatten && self.filters.selects(abs_source, path, true)))
+        {
+            Ok(Decision::CreateDir)
+        } else {
+            Ok(Decision::Skip(None))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn estimate_rejects_conflicting_options() {
+        let tmp = TempDir::new();
+        tmp.write("src/file", "data");
+        let copier = CopyBuilder::new(tmp.join("src"), tmp.join("dst"))
+            .flatten(true)
+            .delete_extraneous(true);
+        assert!(matches!(
+            copier.estimate(),
+            Err(CopyError::ConflictingOptions { .. })
+        ));
+        assert!(matches!(
+            copier.plan().next(),
+            Some(Err(CopyError::ConflictingOptions { .. }))
+        ));
+        assert_eq!(copier.flatten(false).estimate().unwrap().files, 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn destination_in_source_through_symlink() {
+        let tmp = TempDir::new();
+        tmp.write("src/file", "data");
+        std::os::unix::fs::symlink(tmp.join("src"), tmp.join("link")).unwrap();
+        for dest in [tmp.join("link"), tmp.join("link/out")] {
+            let result = CopyBuilder::new(tmp.join("src"), dest).run_with_stats();
+            assert!(matches!(result, Err(CopyError::DestinationInSource { .. })));
+        }
+        // Through a symlinked source as well
+        let result = CopyBuilder::new(tmp.join("link"), tmp.join("src/out")).run_with_stats();
+        assert!(matches!(result, Err(CopyError::DestinationInSource { .. })));
+        assert!(!tmp.join("src/out").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn dangling_symlinks_are_kept_by_default() {
+        let tmp = TempDir::new();
+        tmp.write("src/file", "data");
+        std::os::unix::fs::symlink("/run/missing", tmp.join("src/dangling")).unwrap();
+        assert_eq!(DanglingMode::default(), DanglingMode::Keep);
+        CopyBuilder::new(tmp.join("src"), tmp.join("dst"))
+            .run_with_stats()
+            .unwrap();
+        assert_eq!(
+            read_link(tmp.join("dst/dangling")).unwrap(),
+            Path::new("/run/missing")
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn dangling_symlinks_error() {
+        let tmp = TempDir::new();
+        std::fs::create_dir(tmp.join("src")).unwrap();
+        std::os::unix::fs::symlink("missing", tmp.join("src/dangling")).unwrap();
+        let result = CopyBuilder::new(tmp.join("src"), tmp.join("dst"))
+            .dangling_symlinks(DanglingMode::Error)
+            .run_with_stats();
+        match result {
+            Err(CopyError::DanglingSymlink { link, target }) => {
+                assert_eq!(link, tmp.join("src/dangling"));
+                assert_eq!(target, Path::new("missing"));
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
+        assert!(tmp.join("dst/dangling").symlink_metadata().is_err());
+    }
+
+    #[test]
+    fn resume_keeps_newer_destination() {
+        let tmp = TempDir::new();
+        let source = tmp.write("src/file", "source");
+        let dest = tmp.write("dst/file", "newer destination");
+        let hour_ago = SystemTime::now() - Duration::from_secs(3600);
+        filetime::set_file_mtime(&source, FileTime::from_system_time(hour_ago)).unwrap();
+
+        let stats = CopyBuilder::new(tmp.join("src"), tmp.join("dst"))
+            .resume(true)
+            .protect_newer_destination(true)
+            .run_with_stats()
+            .unwrap();
+        assert_eq!(stats.protected_newer, 1);
+        assert_eq!(std::fs::read_to_string(&dest).unwrap(), "newer destination");
+
+        // Without the protection the incomplete destination is copied again
+        CopyBuilder::new(tmp.join("src"), tmp.join("dst"))
+            .resume(true)
+            .run_with_stats()
+            .unwrap();
+        assert_eq!(std::fs::read_to_string(&dest).unwrap(), "source");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn special_bits_survive_every_copy_path() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tmp = TempDir::new();
+        for (name, mode) in [("setuid", 0o4755), ("setgid", 0o2755)] {
+            let path = tmp.write(format!("src/bin/{}", name), "#!/bin/sh\n");
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode)).unwrap();
+        }
+        std::fs::set_permissions(tmp.join("src/bin"), std::fs::Permissions::from_mode(0o1777))
+            .unwrap();
+
+        let copiers = [
+            (
+                "plain",
+                CopyBuilder::new(tmp.join("src"), tmp.join("plain")),
+            ),
+            (
+                "buffered",
+                CopyBuilder::new(tmp.join("src"), tmp.join("buffered")).buffer_size(3),
+            ),
+            (
+                "reflink",
+                CopyBuilder::new(tmp.join("src"), tmp.join("reflink")).reflink(ReflinkMode::Auto),
+            ),
+        ];
+        for (name, copier) in copiers {
+            copier.preserve_special_bits(true).run_with_stats().unwrap();
+            let mode = |path: &str| {
+                tmp.join(name)
+                    .join(path)
+                    .metadata()
+                    .unwrap()
+                    .permissions()
+                    .mode()
+                    & 0o7777
+            };
+            assert_eq!(mode("bin/setuid"), 0o4755, "{}", name);
+            assert_eq!(mode("bin/setgid"), 0o2755, "{}", name);
+            assert_eq!(mode("bin") & 0o7000, 0o1000, "{}", name);
+        }
+    }
+
+    #[test]
+    fn diff_rejects_conflicting_options() {
+        let tmp = TempDir::new();
+        tmp.write("src/file", "data");
+        let copier = CopyBuilder::new(tmp.join("src"), tmp.join("dst"))
+            .with_rename(|path| Some(path.to_path_buf()))
+            .delete_extraneous(true);
+        assert!(matches!(
+            copier.diff(),
+            Err(CopyError::ConflictingOptions { .. })
+        ));
+        assert!(matches!(
+            copier.plan().next(),
+            Some(Err(CopyError::ConflictingOptions { .. }))
+        ));
+        let diff = copier.delete_extraneous(false).diff().unwrap();
+        assert_eq!(diff.len(), 1);
+        assert_eq!(diff[0].change, Change::Added);
+    }
+
+    #[test]
+    fn copy_one_matches_run() {
+        let tmp = TempDir::new();
+        let copier = |root: &str| {
+            tmp.write(format!("{}/src/build.log", root), "log");
+            tmp.write(format!("{}/src/same", root), "same");
+            tmp.write(format!("{}/dst/same", root), "same");
+            tmp.write(format!("{}/src/new", root), "new");
+            let skipped = Arc::new(std::sync::Mutex::new(vec![]));
+            let src = tmp.join(root).join("src");
+            let copier = CopyBuilder::new(&src, tmp.join(root).join("dst"))
+                .with_exclude_filter(".log")
+                .with_skip_callback({
+                    let skipped = skipped.clone();
+                    move |path, reason| {
+                        let path = path.strip_prefix(&src).unwrap().to_path_buf();
+                        skipped.lock().unwrap().push((path, reason));
+                    }
+                });
+            (copier, skipped)
+        };
+
+        let (run, run_skipped) = copier("run");
+        run.run_with_stats().unwrap();
+        let (one, one_skipped) = copier("one");
+        let decisions: Vec<_> = ["build.log", "same", "new"]
+            .into_iter()
+            .map(|path| one.copy_one(Path::new(path)).unwrap())
+            .collect();
+        assert!(matches!(decisions[0], CopyDecision::Skipped(_)));
+        assert_eq!(
+            decisions[1],
+            CopyDecision::Skipped(Some(SkipReason::UpToDate))
+        );
+        assert_eq!(decisions[2], CopyDecision::Copied(3));
+
+        let mut run_skipped = run_skipped.lock().unwrap().clone();
+        run_skipped.sort_by(|a, b| a.0.cmp(&b.0));
+        let mut one_skipped = one_skipped.lock().unwrap().clone();
+        one_skipped.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(run_skipped, one_skipped);
+        assert_eq!(
+            run_skipped,
+            [
+                (PathBuf::from("build.log"), SkipReason::Excluded),
+                (PathBuf::from("same"), SkipReason::UpToDate),
+            ]
+        );
+        for root in ["run", "one"] {
+            let dst = tmp.join(root).join("dst");
+            assert!(!dst.join("build.log").exists());
+            assert_eq!(std::fs::read_to_string(dst.join("same")).unwrap(), "same");
+            assert_eq!(std::fs::read_to_string(dst.join("new")).unwrap(), "new");
+        }
+    }
+
+    fn assert_conflict(copier: CopyBuilder, expected: (&str, &str)) {
+        match copier.validate() {
+            Err(CopyError::ConflictingOptions { first, second, .. }) => {
+                assert_eq!((first, second), expected)
+            }
+            result => panic!("expected {:?} to conflict, got {:?}", expected, result),
+        }
+    }
+
+    #[test]
+    fn conflict_flatten_delete_extraneous() {
+        assert_conflict(
+            CopyBuilder::new("src", "dst")
+                .flatten(true)
+                .delete_extraneous(true),
+            ("flatten", "delete_extraneous"),
+        );
+    }
+
+    #[test]
+    fn conflict_flatten_rename() {
+        assert_conflict(
+            CopyBuilder::new("src", "dst")
+                .flatten(true)
+                .with_rename(|path| Some(path.to_path_buf())),
+            ("flatten", "with_rename"),
+        );
+    }
+
+    #[test]
+    fn conflict_flatten_overlay_against() {
+        assert_conflict(
+            CopyBuilder::new("src", "dst")
+                .flatten(true)
+                .overlay_against("base"),
+            ("flatten", "overlay_against"),
+        );
+    }
+
+    #[test]
+    fn conflict_fixed_mtime_preserve_timestamps() {
+        assert_conflict(
+            CopyBuilder::new("src", "dst")
+                .with_fixed_mtime(SystemTime::UNIX_EPOCH)
+                .preserve_timestamps(true),
+            ("with_fixed_mtime", "preserve_timestamps"),
+        );
+    }
+
+    #[test]
+    fn conflict_rename_delete_extraneous() {
+        assert_conflict(
+            CopyBuilder::new("src", "dst")
+                .with_rename(|path| Some(path.to_path_buf()))
+                .delete_extraneous(true),
+            ("with_rename", "delete_extraneous"),
+        );
+    }
+
+    #[test]
+    fn no_conflict_by_default() {
+        assert!(CopyBuilder::new("src", "dst").validate().is_ok());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn normalize_relative_symlinks_across_remap() {
+        let tmp = TempDir::new();
+        tmp.write("src/opt/foo/libfoo.so", "foo");
+        std::fs::create_dir_all(tmp.join("src/usr/lib64/foo")).unwrap();
+        std::os::unix::fs::symlink(
+            "../../../opt/foo/libfoo.so",
+            tmp.join("src/usr/lib64/foo/libfoo.so"),
+        )
+        .unwrap();
+        CopyBuilder::new(tmp.join("src"), tmp.join("dst"))
+            .with_remap("/usr/lib64", "/lib")
+            .normalize_relative_symlinks(true)
+            .run_with_stats()
+            .unwrap();
+        let link = tmp.join("dst/lib/foo/libfoo.so");
+        assert_eq!(
+            read_link(&link).unwrap(),
+            Path::new("../../opt/foo/libfoo.so")
+        );
+        assert_eq!(std::fs::read_to_string(link).unwrap(), "foo");
+    }
+
+    #[test]
+    fn conflict_follow_symlinks_preserve_hardlinks() {
+        assert_conflict(
+            CopyBuilder::new("src", "dst")
+                .follow_symlinks(true)
+                .preserve_hardlinks(true),
+            ("follow_symlinks", "preserve_hardlinks"),
+        );
+    }
+
+    #[test]
+    fn conflict_reflink_preserve_hardlinks() {
+        for mode in [ReflinkMode::Auto, ReflinkMode::Always] {
+            assert_conflict(
+                CopyBuilder::new("src", "dst")
+                    .reflink(mode)
+                    .preserve_hardlinks(true),
+                ("reflink", "preserve_hardlinks"),
+            );
+        }
+        assert!(CopyBuilder::new("src", "dst")
+            .reflink(ReflinkMode::Never)
+            .preserve_hardlinks(true)
+            .validate()
+            .is_ok());
+    }
+
+    #[test]
+    fn delete_extraneous_keeps_excluded_entries() {
+        let tmp = TempDir::new();
+        tmp.write("src/keep", "keep");
+        tmp.write("dst/keep", "old");
+        tmp.write("dst/extra", "extra");
+        tmp.write("dst/local.log", "log");
+        tmp.write("dst/cache/data", "cache");
+        std::fs::create_dir_all(tmp.join("dst/empty")).unwrap();
+
+        CopyBuilder::new(tmp.join("src"), tmp.join("dst"))
+            .overwrite(true)
+            .with_exclude_filter(".log")
+            .with_exclude_component("cache")
+            .delete_extraneous(true)
+            .run_with_stats()
+            .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(tmp.join("dst/keep")).unwrap(),
+            "keep"
+        );
+        assert!(tmp.join("dst/local.log").exists());
+        assert!(tmp.join("dst/cache/data").exists());
+        assert!(!tmp.join("dst/extra").exists());
+        assert!(!tmp.join("dst/empty").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn resume_twice_over_symlinks() {
+        let tmp = TempDir::new();
+        tmp.write("src/lib/libc.so.6", "libc");
+        std::os::unix::fs::symlink("libc.so.6", tmp.join("src/lib/libc.so")).unwrap();
+        std::os::unix::fs::symlink("libc.so.6", tmp.join("src/lib/stale.so")).unwrap();
+        let copier = CopyBuilder::new(tmp.join("src"), tmp.join("dst")).resume(true);
+        assert_eq!(copier.run_with_stats().unwrap().symlinks_created, 2);
+
+        // A symlink left pointing elsewhere is replaced, the others are kept
+        std::fs::remove_file(tmp.join("dst/lib/stale.so")).unwrap();
+        std::os::unix::fs::symlink("missing", tmp.join("dst/lib/stale.so")).unwrap();
+        let stats = copier.run_with_stats().unwrap();
+        assert_eq!(stats.symlinks_created, 1);
+        assert_eq!(stats.files_copied, 0);
+        assert_eq!(
+            read_link(tmp.join("dst/lib/stale.so")).unwrap(),
+            Path::new("libc.so.6")
+        );
+        assert_eq!(
+            read_link(tmp.join("dst/lib/libc.so")).unwrap(),
+            Path::new("libc.so.6")
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn follow_symlinks_inside_source() {
+        use std::os::unix::fs::symlink;
+
+        let tmp = TempDir::new();
+        tmp.write("src/usr/lib/libc.so", "libc");
+        tmp.write("src/usr/lib/rel.so", "rel");
+        std::fs::create_dir_all(tmp.join("src/lib")).unwrap();
+        symlink("/usr/lib/libc.so", tmp.join("src/lib/libc.so")).unwrap();
+        symlink("../usr/lib/rel.so", tmp.join("src/lib/rel.so")).unwrap();
+        symlink("/usr/lib", tmp.join("src/lib64")).unwrap();
+        symlink("/usr/lib/missing.so", tmp.join("src/lib/missing.so")).unwrap();
+
+        let skipped = Arc::new(std::sync::Mutex::new(vec![]));
+        let stats = CopyBuilder::new(tmp.join("src"), tmp.join("dst"))
+            .follow_symlinks(true)
+            .with_skip_callback({
+                let skipped = skipped.clone();
+                move |path, reason| skipped.lock().unwrap().push((path.to_path_buf(), reason))
+            })
+            .run_with_stats()
+            .unwrap();
+        assert_eq!(stats.symlinks_created, 0);
+        for (path, contents) in [
+            ("lib/libc.so", "libc"),
+            ("lib/rel.so", "rel"),
+            ("lib64/libc.so", "libc"),
+            ("lib64/rel.so", "rel"),
+        ] {
+            let path = tmp.join("dst").join(path);
+            assert!(!path.is_symlink(), "{}", path.display());
+            assert_eq!(std::fs::read_to_string(path).unwrap(), contents);
+        }
+        assert!(tmp.join("dst/lib64").is_dir() && !tmp.join("dst/lib64").is_symlink());
+        assert!(tmp.join("dst/lib/missing.so").symlink_metadata().is_err());
+        assert_eq!(
+            *skipped.lock().unwrap(),
+            [(tmp.join("src/lib/missing.so"), SkipReason::Dangling)]
+        );
+
+        let one = CopyBuilder::new(tmp.join("src"), tmp.join("one")).follow_symlinks(true);
+        assert_eq!(
+            one.copy_one(Path::new("lib64/libc.so")).unwrap(),
+            CopyDecision::Copied(4)
+        );
+        assert_eq!(
+            std::fs::read_to_string(tmp.join("one/lib64/libc.so")).unwrap(),
+            "libc"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn follow_symlinks_loop() {
+        let tmp = TempDir::new();
+        tmp.write("src/usr/lib/libc.so", "libc");
+        std::os::unix::fs::symlink("/usr", tmp.join("src/usr/lib/loop")).unwrap();
+        let result = CopyBuilder::new(tmp.join("src"), tmp.join("dst"))
+            .follow_symlinks(true)
+            .run_with_stats();
+        match result {
+            Err(CopyError::Io(e)) => {
+                assert_eq!(e.kind(), ErrorKind::Other);
+                assert!(e.to_string().contains("loops back"), "{}", e);
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
     }
 }
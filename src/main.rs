@@ -1,26 +1,75 @@
 use ansi_term::{
-    Color::{Cyan, Green, Red},
+    Color::{Cyan, Green, Red, Yellow},
     Style,
 };
 use anyhow::{anyhow, Context, Result};
-use clap::Parser;
-use inquire::Confirm;
-use make_sysroot::CopyBuilder;
-use serde::Deserialize;
+use clap::{Parser, Subcommand, ValueEnum};
+use filetime::{set_symlink_file_times, FileTime};
+use globset::Glob;
+use inquire::{Confirm, Select};
+use make_sysroot::{
+    assert_safe_destination, available_space, build_sysroot, find_absolute_symlinks, mode::Mode,
+    BuildOptions, BuildReport, Change, CopyBuilder, CopyError, CopyOp, ExtensionStats,
+    FileTypeKind, Precedence, RelativizeStats, Relativizer, SpecialFiles, UpdateReason,
+    VCS_EXCLUDES,
+};
+use serde::{Deserialize, Deserializer};
 use std::{
+    collections::{BTreeMap, HashSet},
+    env::{var, VarError},
     fmt::{Debug, Display},
-    fs::{create_dir_all, read_link, read_to_string, remove_dir_all, remove_file},
-    os::unix::fs::symlink,
-    path::{absolute, PathBuf},
-    process::exit,
+    fs::{read_to_string, set_permissions, Permissions},
+    io::{self, stderr, stdin, IsTerminal, Read},
+    os::unix::fs::PermissionsExt,
+    path::{absolute, Path, PathBuf},
+    process::{exit, Command as Process, ExitCode},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
 use walkdir::WalkDir;
 
-fn main() -> Result<()> {
+fn main() -> ExitCode {
     let args = Args::parse();
-    let src = absolute(args.source).context("Failed to convert source path to an absolute path")?;
-    let dst = absolute(args.destination)
-        .context("Failed to convert destination path to an absolute path")?;
+    let result = match args.command {
+        Some(Command::Relativize { ref dir, dry_run }) => relativize_command(dir, dry_run, &args),
+        Some(Command::CheckRelative { ref dir }) => check_relative(dir),
+        Some(Command::CheckConfig) => check_config(&args),
+        None => build(&args),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => match e.downcast_ref::<Failure>() {
+            Some(Failure::Aborted) => {
+                eprintln!("Aborting");
+                ExitCode::from(Failure::Aborted as u8)
+            }
+            failure => {
+                eprintln!("Error: {:?}", e);
+                ExitCode::from(failure.map_or(1, |failure| *failure as u8))
+            }
+        },
+    }
+}
+
+fn build(args: &Args) -> Result<()> {
+    // Positional paths fill in whichever of the source and destination were not passed as flags
+    let mut paths = args.paths.iter();
+    let source = args.source.as_ref().or_else(|| paths.next());
+    let destination = args.destination.as_ref().or_else(|| paths.next());
+    if paths.next().is_some() {
+        return Err(anyhow!(
+            "Too many paths: pass the source and destination either as flags or positionally"
+        ));
+    }
+    let (Some(source), Some(destination)) = (source, destination) else {
+        return Err(anyhow!(
+            "Both a source and a destination are required: make-sysroot <SOURCE> <DESTINATION>"
+        ));
+    };
+    let src = absolute(source).context("Failed to convert source path to an absolute path")?;
+    let dst =
+        absolute(destination).context("Failed to convert destination path to an absolute path")?;
 
     if src.is_file() {
         return Err(anyhow!("source should be a directory but is a file"));
@@ -31,123 +80,826 @@ fn main() -> Result<()> {
     if !args.force {
         check_dst(&dst)?;
     }
+    if let Some(base) = &args.overlay_base {
+        if !base.is_dir() {
+            return Err(anyhow!(
+                "overlay base {} is not a directory",
+                base.display()
+            ));
+        }
+    }
+
+    let mut config = load_config(&args.config, args.config_format).context(Failure::Config)?;
+    config.exclude_vcs |= args.exclude_vcs;
+    if let Some(on_link_conflict) = args.on_link_conflict {
+        config.on_link_conflict = on_link_conflict;
+    }
+    if !config.hook.is_empty() && !args.allow_hooks {
+        return Err(anyhow!(
+            "The config file defines hooks, which run arbitrary commands. Pass --allow-hooks to run them"
+        )
+        .context(Failure::Config));
+    }
+    if args.list_unsupported {
+        return list_unsupported(copier(&src, &dst, &config, args)?);
+    }
+    if args.diff {
+        return diff(copier(&src, &dst, &config, args)?, args.json);
+    }
+    describe(
+        src.display(),
+        dst.display(),
+        args.overlay_base.as_ref().map(|base| base.display()),
+        &config,
+        !args.no_relativize,
+        args.follow_config_includes_order,
+    );
+    if !confirm(args.yes, "Continue?")? {
+        return Err(Failure::Aborted.into());
+    }
+
+    let mtime = if args.reproducible {
+        Some(source_date_epoch()?)
+    } else {
+        None
+    };
+
+    let copier = copier(&src, &dst, &config, args)?;
+    check_space(&copier, &dst, args)?;
+    let status = Arc::new(Status::new());
+    let options = build_options(&dst, &config, args, &status);
+    let report = build_sysroot(copier, &options);
+    status.clear();
+    let report = report?;
+    print_report(&report, args);
+    apply_chmods(&dst, &config.chmod)?;
+    run_hooks(&dst, &config.hook)?;
+    if let Some(mtime) = mtime {
+        // The steps after the copy create and rewrite entries, so normalize the whole tree
+        normalize_times(&dst, mtime)?;
+    }
+    if !report.failures.is_empty() {
+        return Err(
+            anyhow!("{} entries could not be copied", report.failures.len())
+                .context(Failure::Partial),
+        );
+    }
+    Ok(())
+}
+
+/// Load the config from `path`, or from stdin if it is `-`. Unless given, the format is inferred
+/// from the file extension, defaulting to TOML.
+fn load_config(path: &Path, format: Option<ConfigFormat>) -> Result<Config> {
+    let config_str = if path == Path::new("-") {
+        let mut config_str = String::new();
+        stdin()
+            .read_to_string(&mut config_str)
+            .context("Failed to read config from stdin")?;
+        config_str
+    } else {
+        read_to_string(path).context("Config file not found")?
+    };
+    let format = format.unwrap_or(match path.extension() {
+        Some(extension) if extension == "json" => ConfigFormat::Json,
+        _ => ConfigFormat::Toml,
+    });
+    let mut config: Config = match format {
+        ConfigFormat::Toml => toml::from_str(&config_str)?,
+        ConfigFormat::Json => serde_json::from_str(&config_str)?,
+    };
+    config.expand_env()?;
+    Ok(config)
+}
+
+/// Replace every `${VAR}` in `path` with the value of the environment variable `VAR`, failing if
+/// it is not set. `entry` names the config entry for error messages.
+fn expand_env(path: &Path, entry: &str) -> Result<PathBuf> {
+    let path_str = path.to_string_lossy();
+    let mut rest = path_str.as_ref();
+    if !rest.contains("${") {
+        return Ok(path.to_path_buf());
+    }
+    let mut expanded = String::new();
+    while let Some(start) = rest.find("${") {
+        expanded.push_str(&rest[..start]);
+        let Some(len) = rest[start + 2..].find('}') else {
+            return Err(anyhow!(
+                "Unterminated ${{ in {} entry {}",
+                entry,
+                path.display()
+            ));
+        };
+        let name = &rest[start + 2..start + 2 + len];
+        let value = var(name).with_context(|| {
+            format!(
+                "Environment variable {} used in {} entry {} is not set",
+                name,
+                entry,
+                path.display()
+            )
+        })?;
+        expanded.push_str(&value);
+        rest = &rest[start + 3 + len..];
+    }
+    expanded.push_str(rest);
+    Ok(PathBuf::from(expanded))
+}
+
+/// The timestamp given by `SOURCE_DATE_EPOCH`, or the Unix epoch if it is unset
+fn source_date_epoch() -> Result<SystemTime> {
+    match var("SOURCE_DATE_EPOCH") {
+        Ok(epoch) => {
+            let seconds: u64 = epoch
+                .trim()
+                .parse()
+                .with_context(|| format!("Invalid SOURCE_DATE_EPOCH {}", epoch))?;
+            Ok(UNIX_EPOCH + Duration::from_secs(seconds))
+        }
+        Err(VarError::NotPresent) => Ok(UNIX_EPOCH),
+        Err(e) => Err(e).context("Invalid SOURCE_DATE_EPOCH"),
+    }
+}
+
+fn normalize_times(dst: &Path, mtime: SystemTime) -> Result<()> {
+    let mtime = FileTime::from_system_time(mtime);
+    for entry in WalkDir::new(dst) {
+        let entry = entry?;
+        set_symlink_file_times(entry.path(), mtime, mtime)
+            .with_context(|| format!("Failed to set times of {}", entry.path().display()))?;
+    }
+    Ok(())
+}
+
+fn check_relative(dir: &Path) -> Result<()> {
+    if !dir.is_dir() {
+        return Err(anyhow!("{} is not a directory", dir.display()));
+    }
+    let links = find_absolute_symlinks(dir)?;
+    for (link, target) in links.iter() {
+        println!("{} -> {}", link.display(), target.display());
+    }
+    if !links.is_empty() {
+        return Err(anyhow!(
+            "{} has {} absolute symlinks",
+            dir.display(),
+            links.len()
+        ));
+    }
+    Ok(())
+}
+
+/// Report every problem with the config file without building anything
+fn check_config(args: &Args) -> Result<()> {
+    let config = load_config(&args.config, args.config_format).context(Failure::Config)?;
+    let problems = config.problems();
+    for problem in problems.iter() {
+        println!("{}", Red.bold().paint(problem));
+    }
+    if !problems.is_empty() {
+        return Err(
+            anyhow!("{} has {} problems", args.config.display(), problems.len())
+                .context(Failure::Config),
+        );
+    }
+    println!(
+        "{}",
+        Green
+            .bold()
+            .paint(format!("{} is valid", args.config.display()))
+    );
+    Ok(())
+}
 
-    let config_str = read_to_string(args.config).context("Config file not found")?;
-    let config: Config = toml::from_str(&config_str)?;
-    describe(src.display(), dst.display(), &config);
-    if !query("Continue?")? {
-        eprintln!("Aborting");
-        exit(0);
+fn relativize_command(dir: &Path, dry_run: bool, args: &Args) -> Result<()> {
+    let dir = absolute(dir).context("Failed to convert directory path to an absolute path")?;
+    if !dir.is_dir() {
+        return Err(anyhow!("{} is not a directory", dir.display()));
+    }
+    if !args.force {
+        check_dst(&dir)?;
     }
 
-    copy(&src, &dst, &config)?;
-    create_explicit_symlinks(&dst, config.link)?;
-    make_relative(&dst)?;
+    let relativizer = Relativizer::new(&dir).dry_run(dry_run).force(args.force);
+    if dry_run {
+        let stats = relativize(relativizer)?;
+        println!(
+            "{} absolute symlinks in {} would be converted to their relative equivalents",
+            stats.rewritten,
+            dir.display()
+        );
+        return Ok(());
+    }
+
+    println!(
+        "{}{}: ",
+        Style::new()
+            .bold()
+            .paint("The following operations will occur and "),
+        Red.bold().paint("are possibly destructive")
+    );
+    println!(
+        "    - All symlinks in {} will be converted to their relative equivalents",
+        dir.display()
+    );
+    println!();
+    if !confirm(args.yes, "Continue?")? {
+        return Err(Failure::Aborted.into());
+    }
+    let stats = relativize(relativizer)?;
+    println!(
+        "Converted {} absolute symlinks to their relative equivalents",
+        stats.rewritten
+    );
     Ok(())
 }
 
-fn create_explicit_symlinks(dst: &PathBuf, links: Vec<Link>) -> Result<()> {
-    for link in links {
-        if link.link.is_absolute() {
-            let abs_link = dst.join(link.link.strip_prefix("/")?);
-            if abs_link.symlink_metadata().is_ok() {
+/// Run `relativizer` showing its progress, and warn about converted symlinks that point outside
+/// the sysroot
+fn relativize(relativizer: Relativizer) -> Result<RelativizeStats> {
+    let status = Arc::new(Status::new());
+    let stats = show_progress(relativizer, &status).run();
+    status.clear();
+    let stats = stats?;
+    warn_escaping(&stats);
+    Ok(stats)
+}
+
+/// Show the progress of `relativizer` on `status`
+fn show_progress(relativizer: Relativizer, status: &Arc<Status>) -> Relativizer {
+    let progress = status.clone();
+    relativizer.on_progress(move |progress_info| {
+        progress.update(|| {
+            format!(
+                "Relativizing symlinks: {} processed",
+                progress_info.processed
+            )
+        })
+    })
+}
+
+/// Warn about converted symlinks that point outside the sysroot
+fn warn_escaping(stats: &RelativizeStats) {
+    if stats.escaping > 0 {
+        println!(
+            "{}",
+            Yellow.bold().paint(format!(
+                "Warning: {} converted symlinks point outside the sysroot",
+                stats.escaping
+            ))
+        );
+    }
+}
+
+/// A status line on stderr, only shown if it is a terminal and redrawn at most every 100ms
+struct Status {
+    enabled: bool,
+    last_update: Mutex<Option<Instant>>,
+}
+
+impl Status {
+    fn new() -> Status {
+        Status {
+            enabled: stderr().is_terminal(),
+            last_update: Mutex::new(None),
+        }
+    }
+
+    /// Replace the status line with `message`, unless it was updated too recently
+    fn update<F: FnOnce() -> String>(&self, message: F) {
+        if !self.enabled {
+            return;
+        }
+        let mut last_update = self.last_update.lock().unwrap();
+        if last_update.is_some_and(|last| last.elapsed() < Duration::from_millis(100)) {
+            return;
+        }
+        *last_update = Some(Instant::now());
+        eprint!("\r\x1b[2K{}", message());
+    }
+
+    /// Remove the status line
+    fn clear(&self) {
+        if self.enabled && self.last_update.lock().unwrap().is_some() {
+            eprint!("\r\x1b[2K");
+        }
+    }
+}
+
+/// The steps to run after the copy, as set in the config file and arguments
+fn build_options(dst: &Path, config: &Config, args: &Args, status: &Arc<Status>) -> BuildOptions {
+    let mut options = BuildOptions::new();
+    // Clean up some empty parent directories the copy proccess leaves behind from exlcuded files.
+    // When rules are applied in order, a later rule may include something below an excluded path
+    for rule in config.rules() {
+        match rule {
+            Rule::ExcludePath(path) if !args.follow_config_includes_order => {
+                options = options.with_removed_path(path);
+            }
+            _ => {}
+        }
+    }
+    for mountpoint in config.mountpoint.iter() {
+        options = options.with_mountpoint(&mountpoint.path);
+    }
+    for link in config.link.iter().filter(|link| link.link.is_absolute()) {
+        options = options.with_link(&link.link, &link.target);
+    }
+    let on_conflict = config.on_link_conflict;
+    let yes = args.yes;
+    options = options.on_link_conflict(move |abs_link| {
+        let message = format!(
+            "File {} already exists but was specified for symlinking",
+            &abs_link.to_string_lossy()
+        );
+        if on_conflict == LinkConflict::Error {
+            return Err(CopyError::Io(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                message,
+            )));
+        }
+        println!("{}", Red.bold().paint(message));
+        let replace = match on_conflict {
+            LinkConflict::Prompt => confirm(yes, "Replace it?").map_err(io::Error::other)?,
+            LinkConflict::Replace => true,
+            LinkConflict::Skip | LinkConflict::Error => false,
+        };
+        if !replace {
+            println!("{}", Red.bold().paint("Skipping..."));
+        }
+        Ok(replace)
+    });
+    if !args.no_relativize {
+        options = options.relativize(show_progress(
+            Relativizer::new(dst).force(args.force),
+            status,
+        ));
+    }
+    options
+}
+
+/// Print the type and path of every special file the copy would encounter, one per line
+fn list_unsupported(copier: CopyBuilder) -> Result<()> {
+    // Consider every entry, not only those out of date in the destination
+    let copier = copier
+        .overwrite(true)
+        .overwrite_if_newer(false)
+        .special_files(SpecialFiles::Recreate);
+    for op in copier.plan() {
+        if let CopyOp::Special { source, .. } = op? {
+            match FileTypeKind::of(source.symlink_metadata()?.file_type()) {
+                Some(kind) => println!("{}\t{}", kind, source.display()),
+                None => println!("unknown\t{}", source.display()),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Print what the copy would change in the destination, as JSON if `json` is set. Unchanged
+/// entries are only counted, except in JSON.
+fn diff(copier: CopyBuilder, json: bool) -> Result<()> {
+    let diff = copier.diff()?;
+    if json {
+        let entries: Vec<_> = diff
+            .iter()
+            .map(|entry| {
+                let (change, reason) = match entry.change {
+                    Change::Added => ("added", None),
+                    Change::Updated(reason) => ("updated", Some(update_reason(reason))),
+                    Change::Unchanged => ("unchanged", None),
+                    Change::Removed => ("removed", None),
+                };
+                serde_json::json!({
+                    "path": Path::new("/").join(&entry.path),
+                    "change": change,
+                    "reason": reason,
+                })
+            })
+            .collect();
+        println!("{}", serde_json::to_string_pretty(&entries)?);
+        return Ok(());
+    }
+    let (mut added, mut updated, mut unchanged, mut removed) = (0, 0, 0, 0);
+    for entry in diff.iter() {
+        let path = Path::new("/").join(&entry.path);
+        match entry.change {
+            Change::Added => {
+                added += 1;
                 println!(
-                    "{}",
-                    Red.bold().paint(format!(
-                        "File {} already exists but was specified for symlinking",
-                        &abs_link.to_string_lossy()
-                    ))
+                    "{} {}",
+                    Green.paint("+"),
+                    Green.paint(path.to_string_lossy())
                 );
-                if query("Replace it?")? {
-                    remove_file(&abs_link)?;
-                } else {
-                    println!("{}", Red.bold().paint("Skipping..."));
-                    continue;
-                }
             }
-            create_dir_all(&abs_link.parent().unwrap())?;
-            symlink(link.target, &abs_link)?;
+            Change::Updated(reason) => {
+                updated += 1;
+                println!(
+                    "{} {} ({})",
+                    Yellow.paint("~"),
+                    Yellow.paint(path.to_string_lossy()),
+                    update_reason(reason)
+                );
+            }
+            Change::Unchanged => unchanged += 1,
+            Change::Removed => {
+                removed += 1;
+                println!("{} {}", Red.paint("-"), Red.paint(path.to_string_lossy()));
+            }
         }
     }
+    println!(
+        "{} added, {} updated, {} unchanged, {} removed",
+        added, updated, unchanged, removed
+    );
     Ok(())
 }
 
-fn copy(src: &PathBuf, dst: &PathBuf, config: &Config) -> Result<()> {
-    let mut copier = CopyBuilder::new(&src, &dst).overwrite_if_newer(true);
-    for path in config.include_paths.iter() {
-        copier = copier.with_include_path(
-            src.join(path.strip_prefix("/").with_context(|| {
-                Red.bold().paint(format!(
-                    "The provided include path {} is not absolute",
-                    path.to_string_lossy()
-                ))
-            })?)
-            .to_str()
-            .ok_or_else(|| anyhow!("Failed to parse an include path"))?,
+/// A short description of why a file would be updated
+fn update_reason(reason: UpdateReason) -> &'static str {
+    match reason {
+        UpdateReason::Newer => "newer",
+        UpdateReason::Size => "size",
+        UpdateReason::Content => "content",
+        UpdateReason::Overwrite => "overwrite",
+    }
+}
+
+/// Configure the copy from `src` to `dst`
+fn copier(src: &Path, dst: &Path, config: &Config, args: &Args) -> Result<CopyBuilder> {
+    let mut copier = CopyBuilder::new(src, dst)
+        .overwrite_if_newer(true)
+        .error_on_empty(true)
+        .sorted(args.reproducible);
+    if config.exclude_vcs {
+        copier = copier.with_vcs_excludes();
+    }
+    if config.prune_excluded_links {
+        copier = copier.prune_symlinks_to_excluded(true);
+    }
+    if args.resume {
+        copier = copier.resume(true);
+    }
+    if args.keep_going {
+        copier = copier.continue_on_error(true);
+    }
+    if args.interactive && !args.yes && stdin().is_terminal() {
+        copier = copier.with_overwrite_callback(overwrite_prompt());
+    }
+    if let Some(max) = args.max_parallel_io {
+        copier = copier.with_max_open_files(max);
+    }
+    if args.threads > 1 {
+        copier = copier.threads(args.threads);
+    }
+    if args.atomic {
+        copier = copier.atomic(true);
+    }
+    if let Some(temp_dir) = &args.temp_dir {
+        copier = copier.with_temp_dir(temp_dir);
+    }
+    if let Some(manifest) = &args.origin_manifest {
+        copier = copier.with_origin_manifest(manifest);
+    }
+    if let Some(base) = &args.overlay_base {
+        copier = copier.overlay_against(base);
+    }
+    if let Some(since) = args.since {
+        copier = copier.with_modified_since(since);
+    }
+    if args.follow_config_includes_order {
+        copier = copier.filter_precedence(Precedence::LastMatchWins);
+    }
+    for rule in config.rules() {
+        copier = match rule {
+            Rule::IncludePath(path) => {
+                copier.with_include_path(&source_path(src, &path, "include")?)
+            }
+            Rule::ExcludePath(path) => {
+                copier.with_exclude_path(&source_path(src, &path, "exclude")?)
+            }
+            Rule::IncludeFilter(filter) => copier.with_include_filter(&filter),
+            Rule::ExcludeFilter(filter) => copier.with_exclude_filter(&filter),
+            Rule::ExcludeComponent(name) => copier.with_exclude_component(&name),
+        };
+    }
+    // Mountpoints are created empty after the copy, so nothing below them is copied
+    for mountpoint in config.mountpoint.iter() {
+        copier = copier.with_exclude_path(&source_path(src, &mountpoint.path, "mountpoint")?);
+    }
+    Ok(copier)
+}
+
+/// Ask whether to replace each existing file in the sysroot, until the user picks one of the
+/// options for all remaining files
+fn overwrite_prompt() -> impl Fn(&Path, &Path) -> bool + Send + Sync {
+    const OVERWRITE: &str = "Overwrite";
+    const SKIP: &str = "Skip";
+    const OVERWRITE_ALL: &str = "Overwrite all";
+    const SKIP_ALL: &str = "Skip all";
+    let all = Mutex::new(None);
+    move |source, destination| {
+        let mut all = all.lock().unwrap();
+        if let Some(overwrite) = *all {
+            return overwrite;
+        }
+        println!(
+            "{}",
+            Yellow
+                .bold()
+                .paint(format!("{} already exists", destination.display()))
         );
+        println!("    source:      {}", describe_file(source));
+        println!("    destination: {}", describe_file(destination));
+        let options = vec![OVERWRITE, SKIP, OVERWRITE_ALL, SKIP_ALL];
+        match Select::new("Overwrite it?", options).prompt() {
+            Ok(OVERWRITE) => true,
+            Ok(OVERWRITE_ALL) => {
+                *all = Some(true);
+                true
+            }
+            Ok(SKIP) => false,
+            // Stop asking if the prompt is cancelled
+            _ => {
+                *all = Some(false);
+                false
+            }
+        }
     }
+}
 
-    for path in config.exclude_paths.iter() {
-        copier = copier.with_exclude_path(
-            src.join(path.strip_prefix("/").with_context(|| {
-                Red.bold().paint(format!(
-                    "The provided exclude path {} is not absolute",
-                    path.to_string_lossy()
-                ))
-            })?)
-            .to_str()
-            .ok_or_else(|| anyhow!("Failed to parse an exclude path"))?,
+/// The size and modification time of the file at `path`, for overwrite prompts
+fn describe_file(path: &Path) -> String {
+    let Ok(metadata) = path.metadata() else {
+        return "unreadable".to_owned();
+    };
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|modified| OffsetDateTime::from(modified).format(&Rfc3339).ok())
+        .unwrap_or_else(|| "unknown".to_owned());
+    format!(
+        "{}, modified {}",
+        human_bytes(metadata.len() as f64),
+        modified
+    )
+}
+
+/// Print the statistics and warnings of a build, and every entry that could not be copied with
+/// --keep-going
+fn print_report(report: &BuildReport, args: &Args) {
+    let stats = &report.copy;
+    println!(
+        "Copied {} in {:.1}s, {}/s",
+        human_bytes(stats.bytes_copied as f64),
+        stats.elapsed.as_secs_f64(),
+        human_bytes(stats.bytes_per_sec())
+    );
+    println!(
+        "{} files, {} symlinks and {} directories created, {} entries skipped",
+        stats.files_copied, stats.symlinks_created, stats.dirs_created, stats.files_skipped
+    );
+    if args.stats_by_extension {
+        print_extension_stats(&stats.by_extension);
+    }
+    if args.overlay_base.is_some() {
+        println!(
+            "Created {} whiteouts for entries of the overlay base missing from the source",
+            stats.whiteouts
+        );
+    }
+    if stats.walk_errors > 0 {
+        println!(
+            "{}",
+            Yellow.bold().paint(format!(
+                "Warning: {} entries of the source could not be read and were not copied",
+                stats.walk_errors
+            ))
+        );
+    }
+    if !stats.external_symlinks.is_empty() {
+        println!(
+            "{}",
+            Yellow.bold().paint(format!(
+                "Warning: {} symlinks point outside the source and will not resolve inside the sysroot",
+                stats.external_symlinks.len()
+            ))
+        );
+    }
+    if !stats.unmatched_includes.is_empty() {
+        println!(
+            "{}",
+            Yellow
+                .bold()
+                .paint("Warning: the following includes did not match anything:")
         );
+        for include in stats.unmatched_includes.iter() {
+            println!("    {}", Yellow.paint(include));
+        }
+    }
+    if !report.failures.is_empty() {
+        println!(
+            "{}",
+            Red.bold().paint(format!(
+                "{} entries could not be copied:",
+                report.failures.len()
+            ))
+        );
+        for (path, e) in report.failures.iter() {
+            println!("    {}: {}", Red.paint(path.to_string_lossy()), e);
+        }
     }
 
-    for filter in config.include_filters.iter() {
-        copier = copier.with_include_filter(filter);
+    if let Some(stats) = &report.relativize {
+        warn_escaping(stats);
     }
+}
 
-    for filter in config.exclude_filters.iter() {
-        copier = copier.with_exclude_filter(filter);
+/// Warn, or fail with --check-space, if the files `copier` would copy do not fit in the free
+/// space of `dst`. Files replaced in the destination free up their space, except in an atomic
+/// copy, which writes a complete new tree before removing the old one.
+fn check_space(copier: &CopyBuilder, dst: &Path, args: &Args) -> Result<()> {
+    let estimate = copier.estimate()?;
+    let required = if args.atomic {
+        estimate.total_bytes
+    } else {
+        estimate.required_bytes()
+    };
+    let available = match available_space(dst) {
+        Ok(available) => available,
+        Err(e) if args.check_space => {
+            return Err(e).context("Failed to determine the free space of the destination")
+        }
+        Err(_) => return Ok(()),
+    };
+    if required <= available {
+        return Ok(());
     }
+    let message = format!(
+        "The copy needs {} but only {} is free on the destination",
+        human_bytes(required as f64),
+        human_bytes(available as f64)
+    );
+    if args.check_space {
+        return Err(anyhow!(message).context(Failure::NoSpace));
+    }
+    println!("{}", Yellow.bold().paint(format!("Warning: {}", message)));
+    Ok(())
+}
+
+/// Format a number of bytes with a binary unit, like `4.2 GiB`
+fn human_bytes(bytes: f64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", value, UNITS[unit])
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
 
-    copier.run()?;
+/// Print the copied files and bytes per extension, largest share first
+fn print_extension_stats(by_extension: &BTreeMap<String, ExtensionStats>) {
+    let total: u64 = by_extension.values().map(|stats| stats.bytes).sum();
+    let mut rows: Vec<_> = by_extension.iter().collect();
+    rows.sort_by(|(a_ext, a), (b_ext, b)| b.bytes.cmp(&a.bytes).then(a_ext.cmp(b_ext)));
+    println!(
+        "{}",
+        Style::new().bold().paint(format!(
+            "{:<16} {:>10} {:>16} {:>7}",
+            "Extension", "Files", "Bytes", "Share"
+        ))
+    );
+    for (extension, stats) in rows {
+        let share = if total == 0 {
+            0.0
+        } else {
+            stats.bytes as f64 * 100.0 / total as f64
+        };
+        println!(
+            "{} {:>10} {:>16} {:>6.1}%",
+            Cyan.paint(format!("{:<16}", extension)),
+            stats.files,
+            stats.bytes,
+            share
+        );
+    }
+}
 
-    // Clean up some empty parent directories the copy proccess leaves behind from exlcuded files
-    for path in config.exclude_paths.iter() {
-        let abs_path = dst.join(path.strip_prefix("/")?);
-        if abs_path.exists() {
-            remove_dir_all(&abs_path).context(abs_path.to_string_lossy().into_owned())?;
+fn apply_chmods(dst: &Path, chmods: &[Chmod]) -> Result<()> {
+    for chmod in chmods {
+        let abs_path = dst.join(
+            chmod
+                .path
+                .strip_prefix("/")
+                .with_context(|| {
+                    Red.bold().paint(format!(
+                        "The provided chmod path {} is not absolute",
+                        chmod.path.to_string_lossy()
+                    ))
+                })
+                .context(Failure::Config)?,
+        );
+        let mut walker = WalkDir::new(&abs_path);
+        if !chmod.recursive {
+            walker = walker.max_depth(0);
+        }
+        for entry in walker {
+            let entry = entry.with_context(|| {
+                format!("Failed to apply chmod to {}", abs_path.to_string_lossy())
+            })?;
+            // chmod follows symlinks, so leave them (and their targets) alone
+            if entry.path_is_symlink() {
+                continue;
+            }
+            let metadata = entry.metadata()?;
+            let mode = chmod
+                .mode
+                .apply(metadata.permissions().mode(), metadata.is_dir());
+            set_permissions(entry.path(), Permissions::from_mode(mode))?;
         }
     }
     Ok(())
 }
 
-fn make_relative(sysroot_dir: &PathBuf) -> Result<()> {
-    // Recursively walk through all directories in the sysroot
-    for entry in WalkDir::new(&sysroot_dir) {
-        let entry = entry?;
-        if entry.path_is_symlink() {
-            // Get the target of the symlink
-            let target = read_link(entry.path())?;
-            // Only operate on links who's target is absolute
-            if target.is_absolute() {
-                let real_path = sysroot_dir.join(target.strip_prefix("/")?);
-                // Get target path relative to the entry path
-                let rel_path = pathdiff::diff_paths(
-                    real_path.parent().unwrap(),
-                    entry.path().parent().unwrap(),
-                )
-                .ok_or_else(|| {
-                    anyhow!("Failed to resolve absolute symlink target to a relative one",)
-                })?
-                .join(real_path.file_name().unwrap()); // Preserve the filename of the original target
-                remove_file(entry.path())?;
-                symlink(rel_path, entry.path())?;
+/// Run the command of each hook on every regular file in the destination matching its glob
+fn run_hooks(dst: &Path, hooks: &[Hook]) -> Result<()> {
+    let mut failures = 0;
+    for hook in hooks {
+        let glob = Glob::new(&hook.glob)
+            .with_context(|| format!("Invalid hook glob {}", hook.glob))
+            .context(Failure::Config)?
+            .compile_matcher();
+        for entry in WalkDir::new(dst).sort_by_file_name() {
+            let entry = entry?;
+            if !entry.file_type().is_file() {
+                continue;
+            }
+            // Globs are absolute paths relative to the sysroot, like every other config path
+            let sysroot_path = Path::new("/").join(entry.path().strip_prefix(dst)?);
+            if !glob.is_match(&sysroot_path) {
+                continue;
+            }
+            let path = entry.path().to_string_lossy();
+            // Each argument is passed on as is, so neither it nor the path need quoting
+            let mut argv = hook.command.iter().map(|arg| arg.replace("{path}", &path));
+            let program = argv
+                .next()
+                .ok_or_else(|| anyhow!("Hook for {} has an empty command", hook.glob))?;
+            let output = Process::new(&program)
+                .args(argv)
+                .output()
+                .with_context(|| format!("Failed to run hook command {}", program))?;
+            if !output.status.success() {
+                failures += 1;
+                println!(
+                    "{}",
+                    Red.bold().paint(format!(
+                        "Hook {:?} failed on {} with {}",
+                        hook.command, path, output.status
+                    ))
+                );
+                print!("{}", String::from_utf8_lossy(&output.stderr));
             }
         }
     }
+    if failures > 0 {
+        return Err(anyhow!("{} hook commands failed", failures));
+    }
     Ok(())
 }
 
-fn describe<T: Display>(src: T, dst: T, config: &Config) {
+/// Resolve the absolute config `path` of an include or exclude rule inside `src`
+fn source_path(src: &Path, path: &Path, kind: &str) -> Result<String> {
+    Ok(src
+        .join(
+            path.strip_prefix("/")
+                .with_context(|| {
+                    Red.bold().paint(format!(
+                        "The provided {} path {} is not absolute",
+                        kind,
+                        path.to_string_lossy()
+                    ))
+                })
+                .context(Failure::Config)?,
+        )
+        .to_str()
+        .ok_or_else(|| anyhow!("Failed to parse an {} path", kind))?
+        .to_owned())
+}
+
+fn describe<T: Display>(
+    src: T,
+    dst: T,
+    overlay_base: Option<T>,
+    config: &Config,
+    relativize: bool,
+    ordered: bool,
+) {
     let bold = Style::new().bold();
     println!(
         "{}{}: ",
@@ -155,18 +907,136 @@ fn describe<T: Display>(src: T, dst: T, config: &Config) {
         Red.bold().paint("are possibly destructive")
     );
     println!("    - Any files in the destination directory may be overwritten");
-    println!("    - All symlinks in the destination directory will be converted to their relative equivalents");
+    if overlay_base.is_some() {
+        println!("    - Only a delta layer against the overlay base will be produced: new and changed files, and whiteouts for files missing from the source");
+    }
+    if relativize {
+        println!("    - All symlinks in the destination directory will be converted to their relative equivalents");
+    } else {
+        println!("    - Absolute symlinks in the destination directory will be kept as they are");
+    }
     println!();
     println!("{}", bold.paint("Using the following directories: "));
     println!("{} {}", Green.bold().paint("Source:"), src);
     println!("{} {}", Green.bold().paint("Destination:"), dst);
+    if let Some(base) = overlay_base {
+        println!("{} {}", Green.bold().paint("Overlay base:"), base);
+    }
     println!();
-    let mut includes = config.include_paths.clone();
-    let mut excludes = config.exclude_paths.clone();
-    includes.append(&mut excludes);
-    let mut combined_paths = includes;
+    if ordered {
+        describe_rules(config);
+    } else {
+        describe_sorted_paths(config);
+    }
+    if config.exclude_vcs {
+        println!(
+            "{} {}",
+            bold.paint("Excluding version control directories and build artifacts:"),
+            Red.paint(VCS_EXCLUDES.join(", "))
+        );
+    }
+    if !config.link.is_empty() {
+        println!("{}", bold.paint("The following symlinks will be created: "));
+        for link in config.link.iter() {
+            println!(
+                "{} -> {}",
+                Cyan.paint(link.link.to_string_lossy()),
+                link.target.to_string_lossy()
+            )
+        }
+    }
+    if !config.mountpoint.is_empty() {
+        println!(
+            "{}",
+            bold.paint("The following mountpoints will be created empty: ")
+        );
+        for mountpoint in config.mountpoint.iter() {
+            println!("{}", Cyan.paint(mountpoint.path.to_string_lossy()));
+        }
+    }
+    if !config.chmod.is_empty() {
+        println!(
+            "{}",
+            bold.paint("The following permissions will be changed: ")
+        );
+        for chmod in config.chmod.iter() {
+            println!(
+                "{} {}{}",
+                Cyan.paint(chmod.path.to_string_lossy()),
+                chmod.mode,
+                if chmod.recursive { " (recursive)" } else { "" }
+            )
+        }
+    }
+    if !config.hook.is_empty() {
+        println!(
+            "{}",
+            bold.paint("The following commands will be run on matching files: ")
+        );
+        for hook in config.hook.iter() {
+            println!("{} {:?}", Cyan.paint(&hook.glob), hook.command)
+        }
+    }
+    if !ordered {
+        describe_sorted_filters(config);
+    }
+}
+
+/// Print the include and exclude rules in the order they are applied
+fn describe_rules(config: &Config) {
+    let bold = Style::new().bold();
+    let mut rules = config.rules().peekable();
+    if rules.peek().is_none() {
+        return;
+    }
+    println!(
+        "{} {} {} {}{}",
+        bold.paint("The following rules will be applied in order, the last matching one"),
+        Green.bold().paint("including"),
+        bold.paint("or"),
+        Red.bold().paint("excluding"),
+        bold.paint(" each path:")
+    );
+    for rule in rules {
+        match rule {
+            Rule::IncludePath(path) => println!(
+                "{} {}",
+                Green.paint("+"),
+                Green.paint(path.to_string_lossy())
+            ),
+            Rule::ExcludePath(path) => {
+                println!("{} {}", Red.paint("-"), Red.paint(path.to_string_lossy()))
+            }
+            Rule::IncludeFilter(filter) => println!("{} {}", Green.paint("+"), Green.paint(filter)),
+            Rule::ExcludeFilter(filter) => println!("{} {}", Red.paint("-"), Red.paint(filter)),
+            Rule::ExcludeComponent(name) => {
+                println!("{} {}/", Red.paint("-"), Red.paint(name))
+            }
+        }
+    }
+}
+
+/// Print the include and exclude paths, sorted
+fn describe_sorted_paths(config: &Config) {
+    let bold = Style::new().bold();
+    let includes: Vec<PathBuf> = config
+        .rules()
+        .filter_map(|rule| match rule {
+            Rule::IncludePath(path) => Some(path),
+            _ => None,
+        })
+        .collect();
+    let excludes: Vec<PathBuf> = config
+        .rules()
+        .filter_map(|rule| match rule {
+            Rule::ExcludePath(path) => Some(path),
+            _ => None,
+        })
+        .collect();
+    let mut combined_paths: Vec<PathBuf> =
+        includes.iter().chain(excludes.iter()).cloned().collect();
     combined_paths.sort_unstable();
-    if combined_paths.len() > 0 {
+    if !combined_paths.is_empty() {
         println!(
             "{} {} {} {}{}",
             bold.paint("The following paths will be copied to the destination directory,"),
@@ -176,35 +1046,43 @@ fn describe<T: Display>(src: T, dst: T, config: &Config) {
             bold.paint(":")
         );
         for path in combined_paths {
-            if config.include_paths.contains(&path) {
+            if includes.contains(&path) {
                 println!(
                     "{} {}",
                     Green.paint("+"),
                     Green.paint(path.to_string_lossy())
                 );
-            } else if config.exclude_paths.contains(&path) {
+            } else if excludes.contains(&path) {
                 println!("{} {}", Red.paint("-"), Red.paint(path.to_string_lossy()));
             } else {
                 println!("  {}", path.to_string_lossy());
             }
         }
     }
-    if config.link.len() > 0 {
-        println!("{}", bold.paint("The following symlinks will be created: "));
-        for link in config.link.iter() {
-            println!(
-                "{} -> {}",
-                Cyan.paint(link.link.to_string_lossy()),
-                link.target.to_string_lossy()
-            )
-        }
-    }
-    let mut includes = config.include_filters.clone();
-    let mut excludes = config.exclude_filters.clone();
-    includes.append(&mut excludes);
-    let mut combined_filters = includes;
+}
+
+/// Print the include and exclude filters, sorted
+fn describe_sorted_filters(config: &Config) {
+    let bold = Style::new().bold();
+    let includes: Vec<String> = config
+        .rules()
+        .filter_map(|rule| match rule {
+            Rule::IncludeFilter(filter) => Some(filter),
+            _ => None,
+        })
+        .collect();
+    let excludes: Vec<String> = config
+        .rules()
+        .filter_map(|rule| match rule {
+            Rule::ExcludeFilter(filter) => Some(filter),
+            Rule::ExcludeComponent(name) => Some(format!("{}/", name)),
+            _ => None,
+        })
+        .collect();
+    let mut combined_filters: Vec<String> =
+        includes.iter().chain(excludes.iter()).cloned().collect();
     combined_filters.sort_unstable();
-    if combined_filters.len() > 0 {
+    if !combined_filters.is_empty() {
         println!(
             "{} {} {} {}{}",
             bold.paint("The following filters will be applied,"),
@@ -214,9 +1092,9 @@ fn describe<T: Display>(src: T, dst: T, config: &Config) {
             bold.paint(":")
         );
         for filter in combined_filters {
-            if config.include_filters.contains(&filter) {
+            if includes.contains(&filter) {
                 println!("{} {}", Green.paint("+"), Green.paint(filter));
-            } else if config.exclude_filters.contains(&filter) {
+            } else if excludes.contains(&filter) {
                 println!("{} {}", Red.paint("-"), Red.paint(filter));
             } else {
                 println!("  {}", filter);
@@ -225,45 +1103,212 @@ fn describe<T: Display>(src: T, dst: T, config: &Config) {
     }
 }
 
-fn query<T: Display>(prompt: T) -> Result<bool> {
+/// Ask the user to confirm `prompt`, unless `yes` was passed on the command line
+fn confirm<T: Display>(yes: bool, prompt: T) -> Result<bool> {
+    if yes {
+        return Ok(true);
+    }
     Confirm::new(prompt.to_string().as_str())
         .with_default(false)
         .prompt()
         .context("Context")
 }
 
-fn check_dst(dst: &PathBuf) -> Result<()> {
-    if let Some(dst_str) = dst.canonicalize()?.as_path().to_str() {
-        if dst_str == "/" {
+fn check_dst(dst: &Path) -> Result<()> {
+    match assert_safe_destination(dst) {
+        Err(CopyError::UnsafeDestination(_)) => {
             eprintln!("This will recursively convert every absolute symlink in your root directory to a relative one");
             eprintln!("You probably don't want to do this. If you are sure you want to do this, pass the -f option to override this check");
             exit(1);
         }
-    } else {
-        return Err(anyhow!("Cannot check destination directory"));
+        result => result.context("Cannot check destination directory"),
     }
-    Ok(())
 }
 
+/// Failures with a dedicated exit code. Any other error exits with 1.
+#[derive(Debug, Clone, Copy)]
+enum Failure {
+    /// The config file could not be read or is invalid
+    Config = 2,
+    /// The user declined to continue at a prompt
+    Aborted = 3,
+    /// Some entries could not be copied with --keep-going
+    Partial = 4,
+    /// The copy would not fit on the destination with --check-space
+    NoSpace = 5,
+}
+
+impl Display for Failure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Failure::Config => write!(f, "Invalid config"),
+            Failure::Aborted => write!(f, "Aborted by the user"),
+            Failure::Partial => write!(f, "Only partially copied"),
+            Failure::NoSpace => write!(f, "Not enough free space"),
+        }
+    }
+}
+
+impl std::error::Error for Failure {}
+
 /// A tool for building sysroots for cross compilation
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(version, about, long_about = None, subcommand_negates_reqs = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Source directory to build the sysroot from, then the destination directory to build it in.
+    /// A single path is the destination when --source is passed, or the source when --destination
+    /// is.
+    #[arg(value_names = ["SOURCE", "DESTINATION"], num_args = 0..=2)]
+    paths: Vec<PathBuf>,
+
     /// Source directory to build the sysroot from
     #[arg(short, long)]
-    source: PathBuf,
+    source: Option<PathBuf>,
 
     /// Destination directory to build the sysroot in
     #[arg(short, long)]
-    destination: PathBuf,
+    destination: Option<PathBuf>,
 
-    /// Path to the configuration file
-    #[arg(short, long, default_value = "make-sysroot.toml")]
+    /// Path to the configuration file, or - to read it from stdin
+    #[arg(short, long, global = true, default_value = "make-sysroot.toml")]
     config: PathBuf,
 
+    /// Format of the configuration file [default: from its extension, or toml]
+    #[arg(long, global = true, value_enum)]
+    config_format: Option<ConfigFormat>,
+
     /// Force re-symlinking
-    #[arg(short, long)]
+    #[arg(short, long, global = true)]
     force: bool,
+
+    /// Set the times of everything in the sysroot to SOURCE_DATE_EPOCH (or 0 if unset)
+    #[arg(long)]
+    reproducible: bool,
+
+    /// Keep absolute symlinks as they are instead of converting them to relative ones, for
+    /// sysroots used at their original absolute path
+    #[arg(long)]
+    no_relativize: bool,
+
+    /// What to do when a symlink from the config file already exists in the destination
+    /// [default: the config file's on_link_conflict, or prompt]
+    #[arg(long, value_enum)]
+    on_link_conflict: Option<LinkConflict>,
+
+    /// Exclude version control directories and common build artifacts
+    #[arg(long)]
+    exclude_vcs: bool,
+
+    /// Only copy files modified at or after this RFC 3339 time, e.g. 2024-05-01T12:00:00Z
+    #[arg(long, value_parser = parse_rfc3339)]
+    since: Option<SystemTime>,
+
+    /// Carry on copying when an entry cannot be copied, listing every failure at the end and
+    /// exiting with status 4 if there were any
+    #[arg(long)]
+    keep_going: bool,
+
+    /// Ask before overwriting each file already in the sysroot that the copy would replace.
+    /// Without a terminal or with --yes, files are overwritten without asking
+    #[arg(short, long)]
+    interactive: bool,
+
+    /// Skip files already in the sysroot with the size and modification time of their source, to
+    /// quickly resume an interrupted build
+    #[arg(long)]
+    resume: bool,
+
+    /// Keep at most this many files open at once while copying [default: half the open file limit,
+    /// see ulimit -n]
+    #[arg(long)]
+    max_parallel_io: Option<usize>,
+
+    /// Copy files on this many threads, which is faster on SSDs with many small files
+    #[arg(short = 'j', long, default_value_t = 1)]
+    threads: usize,
+
+    /// Copy into a staging directory and replace the destination with it once the copy is complete
+    #[arg(long)]
+    atomic: bool,
+
+    /// Where to create the staging directory of --atomic [default: next to the destination]
+    #[arg(long, requires = "atomic")]
+    temp_dir: Option<PathBuf>,
+
+    /// Write the source of every file in the sysroot to this file, one
+    /// `<path in sysroot> <TAB> <source path>` line each
+    #[arg(long)]
+    origin_manifest: Option<PathBuf>,
+
+    /// Only copy what is new or differs from this base sysroot, and create whiteouts for what is
+    /// missing from the source, producing a delta layer such as an overlayfs upper directory
+    #[arg(long, value_name = "DIR")]
+    overlay_base: Option<PathBuf>,
+
+    /// Fail instead of warning if the copy will not fit in the free space of the destination
+    #[arg(long)]
+    check_space: bool,
+
+    /// Apply the include and exclude rules in the order they are written in the config file, the
+    /// last one matching a path deciding whether it is copied
+    #[arg(long)]
+    follow_config_includes_order: bool,
+
+    /// Only list the special files in the source, such as FIFOs, sockets and device nodes, one
+    /// `<type> <TAB> <path>` line each, without copying anything
+    #[arg(long)]
+    list_unsupported: bool,
+
+    /// Only print what the copy would add, update and remove in the destination, without copying
+    /// anything. Files are updated if the source is newer, and files only in the destination are
+    /// listed as removed.
+    #[arg(long)]
+    diff: bool,
+
+    /// Print the --diff as JSON, including unchanged files
+    #[arg(long, requires = "diff")]
+    json: bool,
+
+    /// Print how many files and bytes of each file extension were copied
+    #[arg(long)]
+    stats_by_extension: bool,
+
+    /// Run the hook commands defined in the config file
+    #[arg(long)]
+    allow_hooks: bool,
+
+    /// Answer yes to every prompt
+    #[arg(short, long, global = true)]
+    yes: bool,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Only convert the absolute symlinks in an existing directory to relative ones
+    Relativize {
+        /// The directory to convert the symlinks of
+        dir: PathBuf,
+
+        /// Only report how many symlinks would be converted
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// List the absolute symlinks in an existing directory, failing if there are any
+    CheckRelative {
+        /// The directory to check the symlinks of
+        dir: PathBuf,
+    },
+    /// Only check the config file for problems, failing if there are any
+    CheckConfig,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum ConfigFormat {
+    Toml,
+    Json,
 }
 
 #[derive(Deserialize)]
@@ -277,7 +1322,174 @@ struct Config {
     #[serde(default)]
     exclude_filters: Vec<String>,
     #[serde(default)]
+    exclude_components: Vec<String>,
+    #[serde(default)]
     link: Vec<Link>,
+    #[serde(default)]
+    mountpoint: Vec<Mountpoint>,
+    #[serde(default)]
+    chmod: Vec<Chmod>,
+    #[serde(default)]
+    hook: Vec<Hook>,
+    #[serde(default)]
+    rule: Vec<Rule>,
+    #[serde(default)]
+    exclude_vcs: bool,
+    #[serde(default)]
+    prune_excluded_links: bool,
+    #[serde(default)]
+    on_link_conflict: LinkConflict,
+}
+
+impl Config {
+    /// Every include and exclude rule, in the order they are applied: `include_paths`,
+    /// `exclude_paths`, `include_filters`, `exclude_filters` and `exclude_components`, then each
+    /// `rule` as written
+    fn rules(&self) -> impl Iterator<Item = Rule> + '_ {
+        let include_paths = self.include_paths.iter().cloned().map(Rule::IncludePath);
+        let exclude_paths = self.exclude_paths.iter().cloned().map(Rule::ExcludePath);
+        let include_filters = self
+            .include_filters
+            .iter()
+            .cloned()
+            .map(Rule::IncludeFilter);
+        let exclude_filters = self
+            .exclude_filters
+            .iter()
+            .cloned()
+            .map(Rule::ExcludeFilter);
+        let exclude_components = self
+            .exclude_components
+            .iter()
+            .cloned()
+            .map(Rule::ExcludeComponent);
+        include_paths
+            .chain(exclude_paths)
+            .chain(include_filters)
+            .chain(exclude_filters)
+            .chain(exclude_components)
+            .chain(self.rule.iter().cloned())
+    }
+
+    /// Every problem with the config that parsing it does not catch, such as relative paths and
+    /// symlinks defined twice, one message each
+    fn problems(&self) -> Vec<String> {
+        let mut problems = vec![];
+        for rule in self.rules() {
+            let (kind, path) = match &rule {
+                Rule::IncludePath(path) => ("Include", path),
+                Rule::ExcludePath(path) => ("Exclude", path),
+                Rule::IncludeFilter(_) | Rule::ExcludeFilter(_) => continue,
+                Rule::ExcludeComponent(name) => {
+                    if name.is_empty() || name.contains('/') {
+                        problems.push(format!(
+                            "Exclude component \"{}\" is not a single file name",
+                            name
+                        ));
+                    }
+                    continue;
+                }
+            };
+            if !path.is_absolute() {
+                problems.push(format!("{} path {} is not absolute", kind, path.display()));
+            }
+        }
+        let mut links = HashSet::new();
+        for link in self.link.iter() {
+            if !link.link.is_absolute() {
+                problems.push(format!(
+                    "Link {} is not absolute, so it would not be created",
+                    link.link.display()
+                ));
+            } else if !links.insert(&link.link) {
+                problems.push(format!(
+                    "Link {} is defined more than once",
+                    link.link.display()
+                ));
+            }
+        }
+        for mountpoint in self.mountpoint.iter() {
+            if !mountpoint.path.is_absolute() {
+                problems.push(format!(
+                    "Mountpoint {} is not absolute",
+                    mountpoint.path.display()
+                ));
+            }
+        }
+        for chmod in self.chmod.iter() {
+            if !chmod.path.is_absolute() {
+                problems.push(format!(
+                    "Chmod path {} is not absolute",
+                    chmod.path.display()
+                ));
+            }
+        }
+        for hook in self.hook.iter() {
+            if let Err(e) = Glob::new(&hook.glob) {
+                problems.push(format!("Hook glob {} is invalid: {}", hook.glob, e));
+            }
+            if hook
+                .command
+                .first()
+                .is_none_or(|program| program.is_empty())
+            {
+                problems.push(format!("Hook for {} has an empty command", hook.glob));
+            }
+        }
+        problems
+    }
+
+    /// Expand the environment variables in the include and exclude paths, the mountpoints and the
+    /// symlinks, see [`expand_env`]
+    fn expand_env(&mut self) -> Result<()> {
+        for path in self.include_paths.iter_mut() {
+            *path = expand_env(path, "include_paths")?;
+        }
+        for path in self.exclude_paths.iter_mut() {
+            *path = expand_env(path, "exclude_paths")?;
+        }
+        for rule in self.rule.iter_mut() {
+            match rule {
+                Rule::IncludePath(path) => *path = expand_env(path, "rule include_path")?,
+                Rule::ExcludePath(path) => *path = expand_env(path, "rule exclude_path")?,
+                Rule::IncludeFilter(_) | Rule::ExcludeFilter(_) | Rule::ExcludeComponent(_) => {}
+            }
+        }
+        for mountpoint in self.mountpoint.iter_mut() {
+            mountpoint.path = expand_env(&mountpoint.path, "mountpoint")?;
+        }
+        for link in self.link.iter_mut() {
+            link.link = expand_env(&link.link, "link")?;
+            link.target = expand_env(&link.target, "link target")?;
+        }
+        Ok(())
+    }
+}
+
+/// A single include or exclude rule, written as a table with one of the keys
+#[derive(Deserialize, Clone, Debug)]
+#[serde(rename_all = "snake_case")]
+enum Rule {
+    IncludePath(PathBuf),
+    ExcludePath(PathBuf),
+    IncludeFilter(String),
+    ExcludeFilter(String),
+    ExcludeComponent(String),
+}
+
+/// What to do when a symlink from the config file already exists in the destination
+#[derive(Deserialize, ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum LinkConflict {
+    /// Ask whether to replace it, or replace it if --yes is passed
+    #[default]
+    Prompt,
+    /// Keep the existing file
+    Skip,
+    /// Replace the existing file
+    Replace,
+    /// Fail
+    Error,
 }
 
 #[derive(Deserialize, Debug)]
@@ -285,3 +1497,36 @@ struct Link {
     link: PathBuf,
     target: PathBuf,
 }
+
+#[derive(Deserialize, Debug)]
+struct Mountpoint {
+    path: PathBuf,
+}
+
+#[derive(Deserialize, Debug)]
+struct Chmod {
+    path: PathBuf,
+    #[serde(deserialize_with = "deserialize_mode")]
+    mode: Mode,
+    #[serde(default)]
+    recursive: bool,
+}
+
+#[derive(Deserialize, Debug)]
+struct Hook {
+    glob: String,
+    /// The program and its arguments, run without a shell
+    command: Vec<String>,
+}
+
+fn parse_rfc3339(time: &str) -> Result<SystemTime> {
+    Ok(OffsetDateTime::parse(time, &Rfc3339)
+        .with_context(|| format!("Invalid RFC 3339 time {}", time))?
+        .into())
+}
+
+fn deserialize_mode<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Mode, D::Error> {
+    String::deserialize(deserializer)?
+        .parse()
+        .map_err(serde::de::Error::custom)
+}
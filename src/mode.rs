@@ -0,0 +1,412 @@
+//! Parse and apply chmod style mode specifications.
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+/// A file mode specification, either absolute octal or symbolic.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Mode {
+    /// An absolute mode such as `0755`
+    Octal(u32),
+    /// A list of symbolic clauses such as `u+x,go-w`
+    Symbolic(Vec<Clause>),
+}
+
+/// A single comma separated clause of a symbolic mode, e.g. `go-w`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Clause {
+    /// The classes the clause applies to
+    who: Who,
+    /// The operations applied to those classes, in order
+    actions: Vec<Action>,
+}
+
+/// A set of user classes, any of `u`, `g` and `o`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Who {
+    user: bool,
+    group: bool,
+    other: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Action {
+    op: Op,
+    perms: Perms,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Add,
+    Remove,
+    Set,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Perms {
+    /// Any combination of `rwxXst`
+    Bits {
+        read: bool,
+        write: bool,
+        execute: bool,
+        /// `X`: execute only if a directory or already executable by someone
+        search: bool,
+        setid: bool,
+        sticky: bool,
+    },
+    /// Copy the permissions currently held by another class
+    Copy(Class),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Class {
+    User,
+    Group,
+    Other,
+}
+
+/// The error returned when a mode specification cannot be parsed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseModeError {
+    mode: String,
+    reason: String,
+}
+
+impl Display for ParseModeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid mode \"{}\": {}", self.mode, self.reason)
+    }
+}
+
+impl std::error::Error for ParseModeError {}
+
+impl Who {
+    const ALL: Who = Who {
+        user: true,
+        group: true,
+        other: true,
+    };
+
+    /// The permission bits (excluding special bits) covered by these classes
+    fn mask(&self) -> u32 {
+        let mut mask = 0;
+        if self.user {
+            mask |= 0o700;
+        }
+        if self.group {
+            mask |= 0o070;
+        }
+        if self.other {
+            mask |= 0o007;
+        }
+        mask
+    }
+
+    /// Spread a single `rwx` triplet over every class in the set
+    fn spread(&self, triplet: u32) -> u32 {
+        (triplet << 6 | triplet << 3 | triplet) & self.mask()
+    }
+}
+
+impl Class {
+    fn triplet(&self, mode: u32) -> u32 {
+        match self {
+            Class::User => (mode >> 6) & 0o7,
+            Class::Group => (mode >> 3) & 0o7,
+            Class::Other => mode & 0o7,
+        }
+    }
+}
+
+impl Mode {
+    /// Compute the new permission bits of a file from its current `mode`.
+    pub fn apply(&self, mode: u32, is_dir: bool) -> u32 {
+        match self {
+            Mode::Octal(bits) => *bits,
+            Mode::Symbolic(clauses) => clauses
+                .iter()
+                .fold(mode & 0o7777, |mode, clause| clause.apply(mode, is_dir)),
+        }
+    }
+}
+
+impl Clause {
+    fn apply(&self, mut mode: u32, is_dir: bool) -> u32 {
+        for action in &self.actions {
+            let bits = action.perms.bits(&self.who, mode, is_dir);
+            mode = match action.op {
+                Op::Add => mode | bits,
+                Op::Remove => mode & !bits,
+                Op::Set => {
+                    let mut special = 0;
+                    if self.who.user {
+                        special |= 0o4000;
+                    }
+                    if self.who.group {
+                        special |= 0o2000;
+                    }
+                    if self.who.other {
+                        special |= 0o1000;
+                    }
+                    // Directories keep their setuid/setgid bits like GNU chmod
+                    if is_dir {
+                        special &= 0o1000;
+                    }
+                    (mode & !(self.who.mask() | special)) | bits
+                }
+            };
+        }
+        mode
+    }
+}
+
+impl Perms {
+    fn bits(&self, who: &Who, mode: u32, is_dir: bool) -> u32 {
+        match *self {
+            Perms::Bits {
+                read,
+                write,
+                execute,
+                search,
+                setid,
+                sticky,
+            } => {
+                let mut triplet = 0;
+                if read {
+                    triplet |= 0o4;
+                }
+                if write {
+                    triplet |= 0o2;
+                }
+                if execute || (search && (is_dir || mode & 0o111 != 0)) {
+                    triplet |= 0o1;
+                }
+                let mut bits = who.spread(triplet);
+                if setid && who.user {
+                    bits |= 0o4000;
+                }
+                if setid && who.group {
+                    bits |= 0o2000;
+                }
+                if sticky && who.other {
+                    bits |= 0o1000;
+                }
+                bits
+            }
+            Perms::Copy(class) => who.spread(class.triplet(mode)),
+        }
+    }
+}
+
+impl FromStr for Mode {
+    type Err = ParseModeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = |reason: &str| ParseModeError {
+            mode: s.to_owned(),
+            reason: reason.to_owned(),
+        };
+        if s.is_empty() {
+            return Err(error("mode is empty"));
+        }
+        if s.bytes().all(|b| b.is_ascii_digit()) {
+            if s.len() > 4 {
+                return Err(error("octal modes have at most 4 digits"));
+            }
+            return u32::from_str_radix(s, 8)
+                .map(Mode::Octal)
+                .map_err(|_| error("octal digits must be between 0 and 7"));
+        }
+        s.split(',')
+            .map(|clause| parse_clause(clause).map_err(|reason| error(&reason)))
+            .collect::<Result<Vec<_>, _>>()
+            .map(Mode::Symbolic)
+    }
+}
+
+fn parse_clause(clause: &str) -> Result<Clause, String> {
+    let mut chars = clause.chars().peekable();
+    let mut who = Who {
+        user: false,
+        group: false,
+        other: false,
+    };
+    while let Some(c) = chars.next_if(|c| "ugoa".contains(*c)) {
+        match c {
+            'u' => who.user = true,
+            'g' => who.group = true,
+            'o' => who.other = true,
+            _ => who = Who::ALL,
+        }
+    }
+    // Unlike chmod(1), an empty class list is not filtered through the umask
+    if who.mask() == 0 {
+        who = Who::ALL;
+    }
+
+    let mut actions = vec![];
+    while let Some(c) = chars.next() {
+        let op = match c {
+            '+' => Op::Add,
+            '-' => Op::Remove,
+            '=' => Op::Set,
+            _ => return Err(format!("unexpected '{}' in clause \"{}\"", c, clause)),
+        };
+        let copy = chars.next_if(|c| "ugo".contains(*c)).map(|c| match c {
+            'u' => Class::User,
+            'g' => Class::Group,
+            _ => Class::Other,
+        });
+        let perms = match copy {
+            Some(class) => Perms::Copy(class),
+            None => {
+                let (mut read, mut write, mut execute) = (false, false, false);
+                let (mut search, mut setid, mut sticky) = (false, false, false);
+                while let Some(c) = chars.next_if(|c| "rwxXst".contains(*c)) {
+                    match c {
+                        'r' => read = true,
+                        'w' => write = true,
+                        'x' => execute = true,
+                        'X' => search = true,
+                        's' => setid = true,
+                        _ => sticky = true,
+                    }
+                }
+                Perms::Bits {
+                    read,
+                    write,
+                    execute,
+                    search,
+                    setid,
+                    sticky,
+                }
+            }
+        };
+        actions.push(Action { op, perms });
+    }
+    if actions.is_empty() {
+        return Err(format!(
+            "clause \"{}\" has no operation such as '+x'",
+            clause
+        ));
+    }
+    Ok(Clause { who, actions })
+}
+
+impl Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Mode::Octal(bits) => write!(f, "{:04o}", bits),
+            Mode::Symbolic(clauses) => {
+                for (i, clause) in clauses.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", clause)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Display for Clause {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.who == Who::ALL {
+            write!(f, "a")?;
+        } else {
+            for (set, c) in [
+                (self.who.user, 'u'),
+                (self.who.group, 'g'),
+                (self.who.other, 'o'),
+            ] {
+                if set {
+                    write!(f, "{}", c)?;
+                }
+            }
+        }
+        for action in &self.actions {
+            let op = match action.op {
+                Op::Add => '+',
+                Op::Remove => '-',
+                Op::Set => '=',
+            };
+            write!(f, "{}", op)?;
+            match action.perms {
+                Perms::Bits {
+                    read,
+                    write,
+                    execute,
+                    search,
+                    setid,
+                    sticky,
+                } => {
+                    for (set, c) in [
+                        (read, 'r'),
+                        (write, 'w'),
+                        (execute, 'x'),
+                        (search, 'X'),
+                        (setid, 's'),
+                        (sticky, 't'),
+                    ] {
+                        if set {
+                            write!(f, "{}", c)?;
+                        }
+                    }
+                }
+                Perms::Copy(Class::User) => write!(f, "u")?,
+                Perms::Copy(Class::Group) => write!(f, "g")?,
+                Perms::Copy(Class::Other) => write!(f, "o")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply(mode: &str, old: u32, is_dir: bool) -> u32 {
+        mode.parse::<Mode>().unwrap().apply(old, is_dir)
+    }
+
+    #[test]
+    fn octal() {
+        assert_eq!("0755".parse(), Ok(Mode::Octal(0o755)));
+        assert_eq!(apply("4755", 0o644, false), 0o4755);
+    }
+
+    #[test]
+    fn symbolic() {
+        assert_eq!(apply("u+x", 0o644, false), 0o744);
+        assert_eq!(apply("u+x,go-w", 0o666, false), 0o744);
+        assert_eq!(apply("a=r", 0o4755, false), 0o444);
+        assert_eq!(apply("+X", 0o644, false), 0o644);
+        assert_eq!(apply("+X", 0o744, false), 0o755);
+        assert_eq!(apply("+X", 0o644, true), 0o755);
+        assert_eq!(apply("g=u", 0o750, false), 0o770);
+        assert_eq!(apply("u+s,o+t", 0o755, false), 0o5755);
+    }
+
+    #[test]
+    fn set_keeps_directory_setid() {
+        assert_eq!(apply("g=rx", 0o2775, true), 0o2755);
+        assert_eq!(apply("g=rx", 0o2775, false), 0o755);
+    }
+
+    #[test]
+    fn display_round_trips() {
+        for mode in ["0644", "u+x,go-w", "a=r", "g=u", "u+s"] {
+            let parsed: Mode = mode.parse().unwrap();
+            assert_eq!(parsed.to_string().parse(), Ok(parsed));
+        }
+    }
+
+    #[test]
+    fn invalid() {
+        for mode in ["", "8", "07777", "u", "u*x", "u+x,", "z+x"] {
+            let error = mode.parse::<Mode>().unwrap_err();
+            assert!(error.to_string().contains(&format!("\"{}\"", mode)));
+        }
+    }
+}
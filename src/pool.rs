@@ -0,0 +1,64 @@
+//! Spread jobs over a fixed number of worker threads.
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread::Scope;
+
+/// Worker threads running jobs of type `J` as they are submitted, producing results of type `R`
+/// in the order they finish
+pub(crate) struct Pool<J, R> {
+    jobs: SyncSender<J>,
+    results: Receiver<R>,
+}
+
+impl<J: Send, R: Send> Pool<J, R> {
+    /// Start `threads` workers in `scope`, each running `work` on the jobs it takes. Only a few
+    /// jobs per worker are queued, so submitting waits for the workers to catch up.
+    pub(crate) fn new<'scope, 'env, F>(
+        scope: &'scope Scope<'scope, 'env>,
+        threads: usize,
+        work: F,
+    ) -> Pool<J, R>
+    where
+        J: 'scope,
+        R: 'scope,
+        F: Fn(J) -> R + Send + Sync + 'scope,
+    {
+        let (jobs, queue) = mpsc::sync_channel::<J>(threads * 2);
+        let (done, results) = mpsc::channel();
+        let queue = Arc::new(Mutex::new(queue));
+        let work = Arc::new(work);
+        for _ in 0..threads {
+            let queue = queue.clone();
+            let done = done.clone();
+            let work = work.clone();
+            scope.spawn(move || loop {
+                // The lock is released before running the job, so the others can take the next
+                let job = queue.lock().unwrap().recv();
+                let Ok(job) = job else {
+                    break;
+                };
+                if done.send(work(job)).is_err() {
+                    break;
+                }
+            });
+        }
+        Pool { jobs, results }
+    }
+
+    /// Queue `job` for the next free worker
+    pub(crate) fn submit(&self, job: J) {
+        // The workers only stop once the pool is dropped, so this cannot fail
+        let _ = self.jobs.send(job);
+    }
+
+    /// The results of the jobs finished so far, without waiting for any others
+    pub(crate) fn finished(&self) -> impl Iterator<Item = R> + '_ {
+        self.results.try_iter()
+    }
+
+    /// Wait for every submitted job to finish, returning their results
+    pub(crate) fn join(self) -> impl Iterator<Item = R> {
+        drop(self.jobs);
+        self.results.into_iter()
+    }
+}
@@ -0,0 +1,322 @@
+//! Convert the absolute symlinks in a sysroot into their relative equivalents.
+use crate::callback::Callback;
+use crate::resolve::resolve_in_root;
+use crate::{remap_path, CopyBackend, CopyError, StdBackend};
+use log::*;
+use std::fs::{read_link, remove_file};
+use std::io::{Error, ErrorKind};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use walkdir::WalkDir;
+
+#[derive(Debug, Clone)]
+/// Rewrite every absolute symlink below a root directory to a relative one, treating the root
+/// as `/`, so the tree stays valid wherever it is mounted.
+pub struct Relativizer {
+    /// The directory whose symlinks are rewritten
+    pub root: PathBuf,
+    /// Only count the symlinks that would be rewritten
+    dry_run: bool,
+    /// Fail instead of warning on symlinks that would point outside the root
+    strict: bool,
+    /// Directories that are never relativized
+    protected: Vec<PathBuf>,
+    /// Skip the protected directory check
+    force: bool,
+    /// Absolute target prefixes relocated to another prefix before relativizing
+    remaps: Vec<(PathBuf, PathBuf)>,
+    /// Called for every symlink processed
+    on_progress: Option<Callback<ProgressFn>>,
+}
+
+/// The type of [`Relativizer::on_progress`] callbacks
+type ProgressFn = dyn Fn(&RelativizeProgress) + Send + Sync;
+
+#[derive(Debug, Clone, Copy)]
+/// A symlink processed by a [`Relativizer`], see [`Relativizer::on_progress`]
+pub struct RelativizeProgress<'a> {
+    /// The symlink
+    pub link: &'a Path,
+    /// Whether it was rewritten (or would be, in a dry run), or skipped as it is already relative
+    pub rewritten: bool,
+    /// Symlinks processed so far, including this one
+    pub processed: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+/// Statistics about a completed relativization
+pub struct RelativizeStats {
+    /// Absolute symlinks rewritten to relative ones (or that would be, in a dry run)
+    pub rewritten: u64,
+    /// Rewritten symlinks whose relative target climbs out of the root, so they resolve outside
+    /// the sysroot wherever it is located
+    pub escaping: u64,
+}
+
+/// Fail with [`CopyError::UnsafeDestination`] if `dst` is `/`, whose symlinks belong to the
+/// running system and must never be rewritten
+pub fn assert_safe_destination(dst: &Path) -> Result<(), CopyError> {
+    check_protected(dst, &[PathBuf::from("/")])
+}
+
+/// Fail with [`CopyError::UnsafeDestination`] if `dst` resolves to one of the `protected` paths
+fn check_protected(dst: &Path, protected: &[PathBuf]) -> Result<(), CopyError> {
+    let dst = dst.canonicalize()?;
+    for path in protected {
+        if path.canonicalize().is_ok_and(|path| path == dst) {
+            return Err(CopyError::UnsafeDestination(dst));
+        }
+    }
+    Ok(())
+}
+
+/// Find every symlink below `root` with an absolute target, returning each link with its target.
+/// Nothing is modified.
+pub fn find_absolute_symlinks(root: &Path) -> Result<Vec<(PathBuf, PathBuf)>, CopyError> {
+    let mut links = vec![];
+    for entry in WalkDir::new(root).sort_by_file_name() {
+        let entry = entry.map_err(Error::from)?;
+        if !entry.path_is_symlink() {
+            continue;
+        }
+        let target = read_link(entry.path())?;
+        if target.is_absolute() {
+            links.push((entry.into_path(), target));
+        }
+    }
+    Ok(links)
+}
+
+/// Point the symlink `link` at `target` instead, a directory if `is_dir`, leaving it untouched on
+/// platforms without symlinks
+fn replace_symlink(link: &Path, target: &Path, is_dir: bool) -> Result<(), Error> {
+    if cfg!(not(any(unix, windows))) {
+        return Err(Error::new(
+            ErrorKind::Unsupported,
+            format!(
+                "Cannot recreate symlink {} on this platform",
+                link.display()
+            ),
+        ));
+    }
+    remove_file(link)?;
+    if is_dir {
+        StdBackend.create_dir_symlink(target, link)
+    } else {
+        StdBackend.create_symlink(target, link)
+    }
+}
+
+/// Rewrite every absolute symlink below `root` to a relative one
+pub fn relativize_symlinks<P: AsRef<Path>>(root: P) -> Result<RelativizeStats, CopyError> {
+    Relativizer::new(root).run()
+}
+
+impl Relativizer {
+    /// Construct a new Relativizer for `root`.
+    pub fn new<P: AsRef<Path>>(root: P) -> Relativizer {
+        Relativizer {
+            root: root.as_ref().to_path_buf(),
+            dry_run: false,
+            strict: false,
+            protected: vec![PathBuf::from("/")],
+            force: false,
+            remaps: vec![],
+            on_progress: None,
+        }
+    }
+
+    /// Do not modify any symlinks, only count them (off by default)
+    pub fn dry_run(self, dry_run: bool) -> Relativizer {
+        Relativizer { dry_run, ..self }
+    }
+
+    /// Fail with [`CopyError::EscapingSymlink`] instead of logging a warning when a rewritten
+    /// symlink would point outside the root (off by default)
+    pub fn strict(self, strict: bool) -> Relativizer {
+        Relativizer { strict, ..self }
+    }
+
+    /// Also refuse to relativize `path`, in addition to `/` (see [`assert_safe_destination`])
+    pub fn protect<P: AsRef<Path>>(self, path: P) -> Relativizer {
+        let mut protected = self.protected;
+        protected.push(path.as_ref().to_path_buf());
+        Relativizer { protected, ..self }
+    }
+
+    /// Relativize the root even if it is `/` or another protected directory (off by default)
+    pub fn force(self, force: bool) -> Relativizer {
+        Relativizer { force, ..self }
+    }
+
+    /// Resolve absolute targets below `from` below `to` instead, like
+    /// [`CopyBuilder::with_remap`](crate::CopyBuilder::with_remap), so links to remapped paths
+    /// still point at them. The first matching rule wins.
+    pub fn with_remap<P: AsRef<Path>, Q: AsRef<Path>>(self, from: P, to: Q) -> Relativizer {
+        let mut remaps = self.remaps;
+        remaps.push((
+            from.as_ref()
+                .strip_prefix("/")
+                .unwrap_or(from.as_ref())
+                .to_path_buf(),
+            to.as_ref()
+                .strip_prefix("/")
+                .unwrap_or(to.as_ref())
+                .to_path_buf(),
+        ));
+        Relativizer { remaps, ..self }
+    }
+
+    /// Call `on_progress` for every symlink processed, e.g. to display progress on a large tree
+    pub fn on_progress<F>(self, on_progress: F) -> Relativizer
+    where
+        F: Fn(&RelativizeProgress) + Send + Sync + 'static,
+    {
+        Relativizer {
+            on_progress: Some(Callback::new(Arc::new(on_progress))),
+            ..self
+        }
+    }
+
+    /// Execute the relativization
+    pub fn run(&self) -> Result<RelativizeStats, CopyError> {
+        if !self.force && !self.dry_run {
+            check_protected(&self.root, &self.protected)?;
+        }
+        let mut stats = RelativizeStats::default();
+        let mut processed = 0;
+        let report = |link: &Path, rewritten: bool, processed: u64| {
+            if let Some(on_progress) = &self.on_progress {
+                on_progress(&RelativizeProgress {
+                    link,
+                    rewritten,
+                    processed,
+                });
+            }
+        };
+        // Recursively walk through all directories in the sysroot
+        for entry in WalkDir::new(&self.root) {
+            let entry = entry.map_err(Error::from)?;
+            if !entry.path_is_symlink() {
+                continue;
+            }
+            processed += 1;
+            // Only operate on links whose target is absolute
+            let target = read_link(entry.path())?;
+            if !target.is_absolute() {
+                report(entry.path(), false, processed);
+                continue;
+            }
+            let rel_path = self.relative_target(entry.path(), &target).ok_or_else(|| {
+                Error::other(format!(
+                    "Failed to resolve absolute symlink target {} of {} to a relative one",
+                    target.display(),
+                    entry.path().display()
+                ))
+            })?;
+            if self.escapes(entry.path(), &rel_path) {
+                if self.strict {
+                    return Err(CopyError::EscapingSymlink {
+                        link: entry.path().to_path_buf(),
+                        target: rel_path,
+                    });
+                }
+                warn!(
+                    "Symlink {} points to {}, outside of {}",
+                    entry.path().display(),
+                    rel_path.display(),
+                    self.root.display()
+                );
+                stats.escaping += 1;
+            }
+            debug!(
+                "RELATIVIZE {} {} => {}",
+                entry.path().display(),
+                target.display(),
+                rel_path.display()
+            );
+            if !self.dry_run {
+                // Windows tells symlinks to directories apart, going by what the target resolves to
+                let link_dir = entry.path().parent().unwrap_or(&self.root);
+                let is_dir = match link_dir.strip_prefix(&self.root) {
+                    Ok(rel_dir) => resolve_in_root(&self.root, &rel_dir.join(&rel_path))?
+                        .is_some_and(|target| target.is_dir()),
+                    Err(_) => false,
+                };
+                replace_symlink(entry.path(), &rel_path, is_dir)?;
+            }
+            report(entry.path(), true, processed);
+            stats.rewritten += 1;
+        }
+        Ok(stats)
+    }
+
+    /// Determine if following the relative `target` from `link` leaves the root
+    fn escapes(&self, link: &Path, target: &Path) -> bool {
+        let mut resolved = link.parent().unwrap_or(link).to_path_buf();
+        for component in target.components() {
+            match component {
+                Component::ParentDir => {
+                    if !resolved.pop() {
+                        return true;
+                    }
+                }
+                Component::Normal(name) => resolved.push(name),
+                Component::CurDir | Component::RootDir | Component::Prefix(_) => {}
+            }
+            if !resolved.starts_with(&self.root) {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Compute the relative equivalent of the absolute `target` of `link`
+    fn relative_target(&self, link: &Path, target: &Path) -> Option<PathBuf> {
+        let real_path = self
+            .root
+            .join(remap_path(&self.remaps, target.strip_prefix("/").ok()?));
+        // Get target path relative to the link's directory, preserving the filename of the
+        // original target
+        match (real_path.parent(), real_path.file_name()) {
+            (Some(parent), Some(file_name)) => {
+                Some(pathdiff::diff_paths(parent, link.parent()?)?.join(file_name))
+            }
+            _ => pathdiff::diff_paths(&real_path, link.parent()?),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    #[cfg(unix)]
+    #[test]
+    fn rewrites_absolute_symlinks() {
+        let tmp = TempDir::new();
+        tmp.write("usr/lib/libc.so.6", "libc");
+        std::fs::create_dir_all(tmp.join("lib")).unwrap();
+        std::os::unix::fs::symlink("/usr/lib/libc.so.6", tmp.join("lib/libc.so.6")).unwrap();
+        std::os::unix::fs::symlink("libc.so.6", tmp.join("lib/libc.so")).unwrap();
+        std::os::unix::fs::symlink("/usr/lib", tmp.join("lib64")).unwrap();
+
+        let stats = Relativizer::new(tmp.join("")).run().unwrap();
+        assert_eq!(stats.rewritten, 2);
+        assert_eq!(read_link(tmp.join("lib64")).unwrap(), Path::new("usr/lib"));
+        assert!(tmp.join("lib64/libc.so.6").is_file());
+        assert_eq!(
+            read_link(tmp.join("lib/libc.so.6")).unwrap(),
+            Path::new("../usr/lib/libc.so.6")
+        );
+        assert_eq!(
+            read_link(tmp.join("lib/libc.so")).unwrap(),
+            Path::new("libc.so.6")
+        );
+        assert_eq!(
+            std::fs::read_to_string(tmp.join("lib/libc.so")).unwrap(),
+            "libc"
+        );
+    }
+}
@@ -0,0 +1,58 @@
+//! Resolve paths inside a directory tree as if it were the root filesystem.
+use std::collections::VecDeque;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+/// The maximum number of symlinks followed while resolving a single path, like Linux's limit
+const MAX_SYMLINK_HOPS: usize = 40;
+
+/// Resolve `path` inside `root`, following symlinks as if `root` were `/`.
+///
+/// `path` is relative to `root`; absolute paths and absolute symlink targets are interpreted
+/// relative to `root` as well, and `..` never climbs above it. Returns the resolved absolute path
+/// below `root`, or `None` if a component does not exist or the symlinks loop.
+pub(crate) fn resolve_in_root(root: &Path, path: &Path) -> io::Result<Option<PathBuf>> {
+    let mut pending: VecDeque<PathBuf> = components(path).collect();
+    let mut resolved = PathBuf::new();
+    let mut hops = 0;
+
+    while let Some(component) = pending.pop_front() {
+        if component.as_os_str().is_empty() {
+            resolved.clear();
+            continue;
+        }
+        if component == Path::new("..") {
+            resolved.pop();
+            continue;
+        }
+        let candidate = resolved.join(&component);
+        let metadata = match root.join(&candidate).symlink_metadata() {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        if metadata.file_type().is_symlink() {
+            hops += 1;
+            if hops > MAX_SYMLINK_HOPS {
+                return Ok(None);
+            }
+            let target = std::fs::read_link(root.join(&candidate))?;
+            for component in components(&target).rev() {
+                pending.push_front(component);
+            }
+        } else {
+            resolved = candidate;
+        }
+    }
+    Ok(Some(root.join(resolved)))
+}
+
+/// Split a path into its normal and `..` components, yielding an empty path for the root
+fn components(path: &Path) -> impl DoubleEndedIterator<Item = PathBuf> + '_ {
+    path.components().filter_map(|component| match component {
+        Component::RootDir => Some(PathBuf::new()),
+        Component::ParentDir => Some(PathBuf::from("..")),
+        Component::Normal(name) => Some(PathBuf::from(name)),
+        Component::CurDir | Component::Prefix(_) => None,
+    })
+}
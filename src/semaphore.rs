@@ -0,0 +1,48 @@
+//! Limit how many of a resource, such as open files, are used at once.
+use std::sync::{Condvar, Mutex};
+
+#[derive(Debug)]
+/// A counting semaphore handing out up to a fixed number of permits at a time
+pub(crate) struct Semaphore {
+    max: usize,
+    available: Mutex<usize>,
+    released: Condvar,
+}
+
+/// Permits taken from a [`Semaphore`], returned to it when dropped
+pub(crate) struct Permits<'a> {
+    semaphore: &'a Semaphore,
+    count: usize,
+}
+
+impl Semaphore {
+    pub(crate) fn new(max: usize) -> Semaphore {
+        Semaphore {
+            max,
+            available: Mutex::new(max),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Wait until `count` permits are available and take them. Asking for more permits than the
+    /// semaphore has takes all of them.
+    pub(crate) fn acquire(&self, count: usize) -> Permits<'_> {
+        let count = count.min(self.max);
+        let mut available = self.available.lock().unwrap();
+        while *available < count {
+            available = self.released.wait(available).unwrap();
+        }
+        *available -= count;
+        Permits {
+            semaphore: self,
+            count,
+        }
+    }
+}
+
+impl Drop for Permits<'_> {
+    fn drop(&mut self) {
+        *self.semaphore.available.lock().unwrap() += self.count;
+        self.semaphore.released.notify_all();
+    }
+}
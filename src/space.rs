@@ -0,0 +1,38 @@
+//! Query the free space of the filesystem a copy writes to.
+use std::io;
+use std::path::Path;
+
+/// The number of bytes available to unprivileged users on the filesystem containing `path`, or
+/// its closest existing ancestor if it does not exist yet
+pub fn available_space<P: AsRef<Path>>(path: P) -> io::Result<u64> {
+    let path = path
+        .as_ref()
+        .ancestors()
+        .find(|ancestor| ancestor.exists())
+        .unwrap_or(Path::new("."));
+    #[cfg(unix)]
+    {
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+
+        let path = CString::new(path.as_os_str().as_bytes())?;
+        let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+        // SAFETY: `path` is a valid NUL terminated string and `stat` is only read on success
+        if unsafe { libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: statvfs succeeded, so it filled in `stat`
+        let stat = unsafe { stat.assume_init() };
+        // The field types differ between platforms
+        #[allow(clippy::unnecessary_cast)]
+        let available = stat.f_bavail as u64 * stat.f_frsize as u64;
+        Ok(available)
+    }
+    #[cfg(not(unix))]
+    {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("Cannot query the free space of {}", path.display()),
+        ))
+    }
+}
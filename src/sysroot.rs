@@ -0,0 +1,193 @@
+//! Assemble a whole sysroot: copy it, then create its mountpoints and symlinks and make its
+//! symlinks relative.
+use crate::callback::Callback;
+use crate::resolve::resolve_in_root;
+use crate::{CopyBuilder, CopyError, CopyStats, RelativizeStats, Relativizer};
+use log::*;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+#[derive(Debug, Clone, Default)]
+/// The steps [`build_sysroot`] runs after copying. Paths are absolute paths inside the sysroot,
+/// like `/usr/lib`.
+pub struct BuildOptions {
+    /// Removed after copying
+    removed: Vec<PathBuf>,
+    /// Created as empty directories
+    mountpoints: Vec<PathBuf>,
+    /// Created as symlinks, pairs of the link and its target
+    links: Vec<(PathBuf, PathBuf)>,
+    /// Decides whether to replace an entry in the way of a link
+    on_link_conflict: Option<Callback<LinkConflictFn>>,
+    /// Run last, if set
+    relativizer: Option<Relativizer>,
+}
+
+/// The type of [`BuildOptions::on_link_conflict`] callbacks
+type LinkConflictFn = dyn Fn(&Path) -> Result<bool, CopyError> + Send + Sync;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+/// Statistics about the symlinks created by [`build_sysroot`]
+pub struct LinkStats {
+    /// Symlinks created where nothing existed
+    pub created: u64,
+    /// Symlinks created in place of an existing entry
+    pub replaced: u64,
+    /// Symlinks not created as an existing entry was kept
+    pub skipped: u64,
+}
+
+#[derive(Debug, Default)]
+/// The result of [`build_sysroot`]
+pub struct BuildReport {
+    /// Statistics about the copy
+    pub copy: CopyStats,
+    /// Entries that could not be copied, only collected with
+    /// [`CopyBuilder::continue_on_error`]
+    pub failures: Vec<(PathBuf, CopyError)>,
+    /// Mountpoints created, including ones that already existed
+    pub mountpoints: u64,
+    /// Statistics about the symlinks created
+    pub links: LinkStats,
+    /// Statistics about the relativization, if it ran
+    pub relativize: Option<RelativizeStats>,
+}
+
+impl BuildOptions {
+    /// Construct BuildOptions running no steps after the copy
+    pub fn new() -> BuildOptions {
+        BuildOptions::default()
+    }
+
+    /// Remove `path` right after copying, e.g. a directory left behind by excluded files
+    pub fn with_removed_path<P: AsRef<Path>>(self, path: P) -> BuildOptions {
+        let mut removed = self.removed;
+        removed.push(path.as_ref().to_path_buf());
+        BuildOptions { removed, ..self }
+    }
+
+    /// Create `path` as an empty directory, leaving it as it is if it already is a directory in
+    /// case something is mounted on it
+    pub fn with_mountpoint<P: AsRef<Path>>(self, path: P) -> BuildOptions {
+        let mut mountpoints = self.mountpoints;
+        mountpoints.push(path.as_ref().to_path_buf());
+        BuildOptions {
+            mountpoints,
+            ..self
+        }
+    }
+
+    /// Create a symlink at `link` pointing to `target`
+    pub fn with_link<P: AsRef<Path>, Q: AsRef<Path>>(self, link: P, target: Q) -> BuildOptions {
+        let mut links = self.links;
+        links.push((link.as_ref().to_path_buf(), target.as_ref().to_path_buf()));
+        BuildOptions { links, ..self }
+    }
+
+    /// Call `on_link_conflict` with the path of every existing entry a symlink would be created
+    /// at, replacing it if it returns `Ok(true)` and keeping it if it returns `Ok(false)`.
+    /// Without one, an existing entry fails the build.
+    pub fn on_link_conflict<F>(self, on_link_conflict: F) -> BuildOptions
+    where
+        F: Fn(&Path) -> Result<bool, CopyError> + Send + Sync + 'static,
+    {
+        BuildOptions {
+            on_link_conflict: Some(Callback::new(Arc::new(on_link_conflict))),
+            ..self
+        }
+    }
+
+    /// Run `relativizer` once everything else is done. Its root should be the destination.
+    pub fn relativize(self, relativizer: Relativizer) -> BuildOptions {
+        BuildOptions {
+            relativizer: Some(relativizer),
+            ..self
+        }
+    }
+}
+
+/// Copy a sysroot with `copier`, then remove, create and relativize entries in its destination
+/// as set in `options`, in that order
+pub fn build_sysroot(
+    copier: CopyBuilder,
+    options: &BuildOptions,
+) -> Result<BuildReport, CopyError> {
+    let (copy, failures) = copier.run_with_failures()?;
+    let dst = &copier.destination;
+    let mut report = BuildReport {
+        copy,
+        failures,
+        ..BuildReport::default()
+    };
+
+    for path in options.removed.iter() {
+        let abs_path = dst.join(path.strip_prefix("/").unwrap_or(path));
+        if abs_path.exists() {
+            debug!("RMDIR {}", abs_path.display());
+            std::fs::remove_dir_all(&abs_path)?;
+        }
+    }
+
+    for mountpoint in options.mountpoints.iter() {
+        let abs_path = dst.join(mountpoint.strip_prefix("/").unwrap_or(mountpoint));
+        if abs_path.symlink_metadata().is_ok() && !abs_path.is_dir() {
+            return Err(Error::new(
+                ErrorKind::AlreadyExists,
+                format!(
+                    "Mountpoint {} already exists but is not a directory",
+                    abs_path.display()
+                ),
+            )
+            .into());
+        }
+        debug!("MKDIR {}", abs_path.display());
+        copier.backend.create_dir(&abs_path)?;
+        report.mountpoints += 1;
+    }
+
+    for (link, target) in options.links.iter() {
+        let abs_link = dst.join(link.strip_prefix("/").unwrap_or(link));
+        let existing = abs_link.symlink_metadata().is_ok();
+        if existing {
+            let replace = match &options.on_link_conflict {
+                Some(on_link_conflict) => on_link_conflict(&abs_link)?,
+                None => {
+                    return Err(Error::new(
+                        ErrorKind::AlreadyExists,
+                        format!(
+                            "File {} already exists but was specified for symlinking",
+                            abs_link.display()
+                        ),
+                    )
+                    .into())
+                }
+            };
+            if !replace {
+                report.links.skipped += 1;
+                continue;
+            }
+            std::fs::remove_file(&abs_link)?;
+        }
+        if let Some(parent) = abs_link.parent() {
+            copier.backend.create_dir(parent)?;
+        }
+        debug!("LN {} -> {}", abs_link.display(), target.display());
+        let link_dir = link.parent().unwrap_or(Path::new("/"));
+        if resolve_in_root(dst, &link_dir.join(target))?.is_some_and(|target| target.is_dir()) {
+            copier.backend.create_dir_symlink(target, &abs_link)?;
+        } else {
+            copier.backend.create_symlink(target, &abs_link)?;
+        }
+        if existing {
+            report.links.replaced += 1;
+        } else {
+            report.links.created += 1;
+        }
+    }
+
+    if let Some(relativizer) = &options.relativizer {
+        report.relativize = Some(relativizer.run()?);
+    }
+    Ok(report)
+}